@@ -1,19 +1,29 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::{BufWriter, Write},
-    num::NonZeroI32,
-    sync::Arc,
-    time::Instant,
-};
-
-use anyhow::{anyhow, Result};
+use std::{num::NonZeroI32, ops::Range, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, bail, Result};
 use binseq::BinseqRecord;
+use flate2::Compression;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use minimap2::{Aligner, Built, Mapping, Strand};
+use minimap2::{Aligner, Built, IdxOpt, Mapping, Strand};
 use paraseq::{parallel::ProcessError, Record};
 use parking_lot::Mutex;
 use serde::Serialize;
 
+use crate::bam::{self, BamSorter, IndexBuilder};
+use crate::bgzf::{self, BgzfWriter};
+use crate::cli::{CsFormat, OutputCompression, OutputFormat};
+use crate::counts::{CountsTable, LocalCounts};
+use crate::filter::{FilterStats, ReadFilter};
+use crate::mapper::{self, ChainScores, Mapped};
+use crate::names::QueryNaming;
+use crate::ordered::ReorderBuffer;
+use crate::reads::ReadWriter;
+use crate::reference::{LabelSummary, ReferenceLabels};
+use crate::sam::{self, Mate, Query};
+use crate::sample::{Sampler, SamplingSummary};
+use crate::sink::OutputSink;
+use crate::stats::{MappingStats, MappingSummary};
+
 #[derive(Clone)]
 pub struct ParallelAlignment {
     aligner: Arc<Aligner<Built>>,
@@ -21,22 +31,95 @@ pub struct ParallelAlignment {
     /// Local buffer for decoding records
     dbuf: Vec<u8>,
 
-    /// Local write buffer for PAF records
+    /// Local buffer for quality scores of decoded records
+    qbuf: Vec<u8>,
+
+    /// Local buffers for the extended sequence (and quality scores) of paired records
+    xbuf: Vec<u8>,
+    xqbuf: Vec<u8>,
+
+    /// Local buffer for the names of BINSEQ records
+    nbuf: Vec<u8>,
+
+    /// Local write buffer for output records (swapped for a pooled buffer once sent)
     wbuf: Vec<u8>,
 
     /// Path name for the output file
     output_path: Option<String>,
 
-    /// IO lock
-    io_lock: Arc<Mutex<()>>,
+    /// Output of the alignment records (written by a dedicated thread)
+    output: OutputSink,
+
+    /// Reorders batch outputs into input order (only with ordered output)
+    order: Option<Arc<ReorderBuffer>>,
+
+    /// Range of input positions covered by the current batch
+    batch: Option<(u64, u64)>,
 
     /// Cigar option
     with_cigar: bool,
 
+    /// Form of the cs tag (if requested)
+    cs: Option<CsFormat>,
+
+    /// Whether to output the MD tag
+    md: bool,
+
+    /// Whether to write placeholder records for queries without hits
+    report_unmapped: bool,
+
+    /// Outputs of the raw query records with and without mappings
+    mapped_out: Option<ReadWriter>,
+    unmapped_out: Option<ReadWriter>,
+
+    /// Per-target alignment counts (shared table and local counts)
+    counts: Option<Arc<CountsTable>>,
+    local_counts: LocalCounts,
+
+    /// Labels of the reference sequences and the reads assigned to each label (local/global)
+    labels: Option<Arc<ReferenceLabels>>,
+    local_label_reads: Vec<u64>,
+    global_label_reads: Arc<Mutex<Vec<u64>>>,
+
+    /// Whether alignment records are written (disabled when only counting)
+    write_alignments: bool,
+
+    /// Output format
+    format: OutputFormat,
+
+    /// Compression of the output (BAM is always BGZF-compressed by the workers)
+    compression: OutputCompression,
+
+    /// Coordinate sorter for BAM records (only when sorting)
+    sorter: Option<Arc<Mutex<Option<BamSorter>>>>,
+
+    /// Read groups declared in the output header
+    read_groups: Arc<Vec<String>>,
+
+    /// Read group of the current query input (tagged on every record)
+    read_group: Option<Arc<str>>,
+
+    /// Names of the BINSEQ records of the current query input
+    naming: Arc<QueryNaming>,
+
     /// Number of records processed (local/global)
     local_n_processed: usize,
     global_n_processed: Arc<Mutex<usize>>,
 
+    /// Subsampling of the query records (if requested)
+    sampler: Option<Arc<Sampler>>,
+
+    /// Pre-mapping read filters (if requested)
+    filter: Option<Arc<ReadFilter>>,
+
+    /// Number of records passed or rejected by the filters (local/global)
+    local_filter_stats: FilterStats,
+    global_filter_stats: Arc<Mutex<FilterStats>>,
+
+    /// Mapping QC of the written reads (local/global)
+    local_stats: MappingStats,
+    global_stats: Arc<Mutex<MappingStats>>,
+
     /// Start time
     start_time: Instant,
 
@@ -51,30 +134,249 @@ impl ParallelAlignment {
         aligner: Aligner<Built>,
         output_path: Option<String>,
         with_cigar: bool,
+        format: OutputFormat,
+        compression: OutputCompression,
+        sorter: Option<BamSorter>,
+        read_groups: Vec<String>,
     ) -> Result<Self> {
-        Self::initialize_output(output_path.as_ref())?;
+        let output = OutputSink::create(output_path.as_deref(), compression)?;
+        Self::initialize_output(&output, &aligner, format, sorter.is_some(), &read_groups)?;
         let pbar = Self::initialize_pbar();
         Ok(Self {
             aligner: Arc::new(aligner),
             dbuf: Vec::new(),
+            qbuf: Vec::new(),
+            xbuf: Vec::new(),
+            xqbuf: Vec::new(),
+            nbuf: Vec::new(),
             wbuf: Vec::new(),
-            io_lock: Arc::new(Mutex::new(())),
+            output,
+            order: None,
+            batch: None,
             local_n_processed: 0,
             global_n_processed: Arc::new(Mutex::new(0)),
+            sampler: None,
+            filter: None,
+            local_filter_stats: FilterStats::default(),
+            global_filter_stats: Arc::new(Mutex::new(FilterStats::default())),
+            local_stats: MappingStats::default(),
+            global_stats: Arc::new(Mutex::new(MappingStats::default())),
             output_path,
             start_time: Instant::now(),
             tid: 0,
             pbar: Arc::new(Mutex::new(pbar)),
             with_cigar,
+            cs: None,
+            md: false,
+            report_unmapped: false,
+            mapped_out: None,
+            unmapped_out: None,
+            counts: None,
+            local_counts: LocalCounts::new(),
+            labels: None,
+            local_label_reads: Vec::new(),
+            global_label_reads: Arc::new(Mutex::new(Vec::new())),
+            write_alignments: true,
+            format,
+            compression,
+            sorter: sorter.map(|sorter| Arc::new(Mutex::new(Some(sorter)))),
+            read_groups: Arc::new(read_groups),
+            read_group: None,
+            naming: Arc::new(QueryNaming::default()),
         })
     }
-    pub fn initialize_output(output_path: Option<&String>) -> Result<()> {
-        if let Some(path) = output_path {
-            File::create(path)?;
-            Ok(())
-        } else {
-            Ok(())
+
+    /// Redirects the output to a new file
+    ///
+    /// The previous output must be closed with `finish_output` beforehand.
+    pub fn set_output(
+        &mut self,
+        output_path: Option<String>,
+        sorter: Option<BamSorter>,
+    ) -> Result<()> {
+        let output = OutputSink::create(output_path.as_deref(), self.compression)?;
+        Self::initialize_output(
+            &output,
+            &self.aligner,
+            self.format,
+            sorter.is_some(),
+            &self.read_groups,
+        )?;
+        self.output = output;
+        self.output_path = output_path;
+        self.sorter = sorter.map(|sorter| Arc::new(Mutex::new(Some(sorter))));
+        Ok(())
+    }
+
+    /// Takes over the per-input configuration (output, read group, and naming) of another aligner
+    pub fn set_input(&mut self, input: &ParallelAlignment) {
+        self.output = input.output.clone();
+        self.output_path = input.output_path.clone();
+        self.sorter = input.sorter.clone();
+        self.read_group = input.read_group.clone();
+        self.naming = input.naming.clone();
+    }
+
+    /// Sets the read group tagged on the records of the next query input
+    pub fn set_read_group(&mut self, read_group: Option<&str>) {
+        self.read_group = read_group.map(Arc::from);
+    }
+
+    /// Sets how the BINSEQ records of the next query input are named
+    pub fn set_query_naming(&mut self, naming: QueryNaming) {
+        self.naming = Arc::new(naming);
+    }
+
+    /// Sets which difference strings (cs and MD tags) are computed for each alignment
+    pub fn set_difference_strings(&mut self, cs: Option<CsFormat>, md: bool) {
+        self.cs = cs;
+        self.md = md;
+    }
+
+    /// Sets whether placeholder records are written for queries without hits
+    pub fn set_report_unmapped(&mut self, report_unmapped: bool) {
+        self.report_unmapped = report_unmapped;
+    }
+
+    /// Sets the outputs the raw query records are written to (split by whether they mapped)
+    pub fn set_read_outputs(
+        &mut self,
+        mapped_out: Option<ReadWriter>,
+        unmapped_out: Option<ReadWriter>,
+    ) {
+        self.mapped_out = mapped_out;
+        self.unmapped_out = unmapped_out;
+    }
+
+    /// Sets the table primary alignments are counted into per target
+    pub fn set_counts(&mut self, counts: Option<CountsTable>) {
+        self.counts = counts.map(Arc::new);
+    }
+
+    /// Sets the labels alignments are tagged with (and reads are summarized by)
+    pub fn set_reference_labels(&mut self, labels: Option<ReferenceLabels>) {
+        let n_labels = labels.as_ref().map_or(0, ReferenceLabels::n_labels);
+        self.local_label_reads = vec![0; n_labels];
+        self.global_label_reads = Arc::new(Mutex::new(vec![0; n_labels]));
+        self.labels = labels.map(Arc::new);
+    }
+
+    /// Sets whether alignment records are written to the output
+    pub fn set_write_alignments(&mut self, write_alignments: bool) {
+        self.write_alignments = write_alignments;
+    }
+
+    /// Writes the per-target counts table
+    pub fn finish_counts(&self) -> Result<()> {
+        match &self.counts {
+            Some(counts) => counts.write(&sam::reference_sequences(&self.aligner)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the subsampling of the query records
+    pub fn set_sampler(&mut self, sampler: Option<Sampler>) {
+        self.sampler = sampler.map(Arc::new);
+    }
+
+    /// Starts counting the records kept from a new query input
+    pub fn reset_sampler(&self) {
+        if let Some(sampler) = &self.sampler {
+            sampler.reset();
+        }
+    }
+
+    /// Shortens a range of BINSEQ records to the records kept by subsampling
+    pub fn sample_range(&self, range: Range<usize>) -> Range<usize> {
+        match &self.sampler {
+            Some(sampler) => sampler.limit_range(range),
+            None => range,
+        }
+    }
+
+    /// Subsampling of the query records (applied to FASTQ/FASTA records by the reading thread)
+    pub fn sampler(&self) -> Option<&Sampler> {
+        self.sampler.as_deref()
+    }
+
+    /// Whether the BINSEQ record at an index is kept by subsampling
+    fn sample_index(&self, index: u64) -> bool {
+        self.sampler
+            .as_ref()
+            .is_none_or(|sampler| sampler.select_index(index))
+    }
+
+    /// Sets the filters reads must pass to be mapped
+    pub fn set_filter(&mut self, filter: Option<ReadFilter>) {
+        self.filter = filter.map(Arc::new);
+    }
+
+    /// Whether a read (and its mate) passes the filters (counting rejected records)
+    fn passes_filters(
+        &mut self,
+        read: (&[u8], Option<&[u8]>),
+        mate: Option<(&[u8], Option<&[u8]>)>,
+    ) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        let rejection = filter.check(read, mate);
+        self.local_filter_stats.add(rejection);
+        rejection.is_none()
+    }
+
+    /// Whether the decoded BINSEQ record passes the filters (counting rejected records)
+    fn passes_filters_decoded(&mut self, paired: bool) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        let rejection = filter.check(
+            (&self.dbuf, Some(&self.qbuf)),
+            paired.then_some((&self.xbuf, Some(&self.xqbuf))),
+        );
+        self.local_filter_stats.add(rejection);
+        rejection.is_none()
+    }
+
+    /// Sets the reorder buffer batches are written through (to keep the input order)
+    pub fn set_order(&mut self, order: Option<Arc<ReorderBuffer>>) {
+        self.order = order;
+    }
+
+    /// Starts a batch covering a half-open range of input positions (see `ReorderBuffer`)
+    pub fn begin_batch(&mut self, start: u64, end: u64) {
+        self.batch = Some((start, end));
+    }
+
+    /// Closes the outputs of the raw query records
+    pub fn finish_read_outputs(&self) -> Result<()> {
+        for output in [&self.mapped_out, &self.unmapped_out].into_iter().flatten() {
+            output.finish()?;
+        }
+        Ok(())
+    }
+    pub fn initialize_output(
+        output: &OutputSink,
+        aligner: &Aligner<Built>,
+        format: OutputFormat,
+        sorted: bool,
+        read_groups: &[String],
+    ) -> Result<()> {
+        // Sorted output is written in full once mapping is complete
+        if sorted {
+            return Ok(());
+        }
+        let mut header = output.buffer();
+        match format {
+            OutputFormat::Paf => {}
+            OutputFormat::Sam => sam::write_header(&mut header, aligner, read_groups, false)?,
+            OutputFormat::Bam => bgzf::compress_blocks(
+                &bam::encode_header(aligner, read_groups, false)?,
+                Compression::default(),
+                &mut header,
+            )?,
         }
+        output.send(header)
     }
     pub fn initialize_pbar() -> ProgressBar {
         let pbar = ProgressBar::new_spinner();
@@ -89,48 +391,255 @@ impl ParallelAlignment {
 
     fn decode_record<B: BinseqRecord>(&mut self, record: B) -> Result<(), binseq::Error> {
         self.dbuf.clear();
+        self.qbuf.clear();
         record.decode_s(&mut self.dbuf)?;
+        self.qbuf.extend_from_slice(record.squal());
+        if record.is_paired() {
+            self.xbuf.clear();
+            self.xqbuf.clear();
+            record.decode_x(&mut self.xbuf)?;
+            self.xqbuf.extend_from_slice(record.xqual());
+        }
         Ok(())
     }
 
-    fn reopen_handle(&self) -> Result<Box<dyn Write>> {
-        if let Some(path) = &self.output_path {
-            let file = OpenOptions::new().append(true).open(path)?;
-            let buffer = BufWriter::new(file);
-            Ok(Box::new(buffer))
+    /// Maps a mate pair of FASTQ records after validating their names
+    fn process_fastq_pair<Rf: Record>(&mut self, r1: Rf, r2: Rf) -> Result<()> {
+        let name = sam::template_name(r1.id());
+        if name != sam::template_name(r2.id()) {
+            bail!(
+                "Mismatched read names in mate pair: {} and {}",
+                String::from_utf8_lossy(r1.id()),
+                String::from_utf8_lossy(r2.id())
+            );
+        }
+        let (s1, s2) = (r1.seq(), r2.seq());
+        if !self.passes_filters((&s1, r1.qual()), Some((&s2, r2.qual()))) {
+            return Ok(());
+        }
+        self.local_n_processed += 1;
+        self.map_pair(
+            name,
+            Query {
+                name,
+                seq: &s1,
+                qual: r1.qual(),
+                mate: None,
+            },
+            Query {
+                name,
+                seq: &s2,
+                qual: r2.qual(),
+                mate: None,
+            },
+        )
+    }
+
+    /// Maps both segments of a pair as a fragment and writes the mate-aware records
+    fn map_pair(&mut self, name: &[u8], r1: Query, r2: Query) -> Result<()> {
+        let (m1, m2) = mapper::map_pair(&self.aligner, r1.seq, r2.seq, self.cs, self.md, name)
+            .map_err(|err| anyhow!("Error mapping record pair: {}", err))?;
+        self.write_reads(&r1, Some(&r2), !m1.is_empty() || !m2.is_empty())?;
+        let (mate1, mate2) = Mate::pair(&m1, &m2);
+        self.write_local(
+            Query {
+                mate: Some(mate1),
+                ..r1
+            },
+            &m1,
+        )?;
+        self.write_local(
+            Query {
+                mate: Some(mate2),
+                ..r2
+            },
+            &m2,
+        )
+    }
+
+    /// Writes the raw query (and mate) to the mapped or unmapped read output
+    ///
+    /// Pairs count as mapped if either mate has a mapping.
+    fn write_reads(&mut self, query: &Query, mate: Option<&Query>, mapped: bool) -> Result<()> {
+        let output = if mapped {
+            self.mapped_out.as_mut()
         } else {
-            let file = std::io::stdout();
-            let buffer = BufWriter::new(file);
-            Ok(Box::new(buffer))
+            self.unmapped_out.as_mut()
+        };
+        if let Some(output) = output {
+            output.write(query, mate)?;
+        }
+        Ok(())
+    }
+
+    fn write_local(&mut self, query: Query, mapped: &Mapped) -> Result<()> {
+        let mapping = &mapped.mappings[..];
+        self.local_stats.add_read(query.seq.len(), mapping);
+        if let Some(counts) = &self.counts {
+            counts.count(&mut self.local_counts, mapping);
         }
+        if let Some(labels) = &self.labels {
+            labels.count(&mut self.local_label_reads, mapping);
+        }
+        if !self.write_alignments {
+            return Ok(());
+        }
+        if mapping.is_empty() && self.report_unmapped {
+            return self.write_unmapped(&query, mapped.repeat_len);
+        }
+        match self.format {
+            OutputFormat::Paf => self.write_paf(&query, mapped),
+            OutputFormat::Sam => sam::write_records(
+                &mut self.wbuf,
+                &query,
+                mapping,
+                self.labels.as_deref(),
+                self.read_group.as_deref(),
+            ),
+            OutputFormat::Bam => bam::encode_records(
+                &mut self.wbuf,
+                &query,
+                mapping,
+                self.labels.as_deref(),
+                self.read_group.as_deref(),
+            ),
+        }
+    }
+    fn write_unmapped(&mut self, query: &Query, repeat_len: i32) -> Result<()> {
+        match self.format {
+            OutputFormat::Paf => {
+                self.write_paf_records(query, [MappingNutype::unmapped(query, repeat_len)])
+            }
+            OutputFormat::Sam => {
+                sam::write_unmapped(&mut self.wbuf, query, self.read_group.as_deref())
+            }
+            OutputFormat::Bam => {
+                bam::encode_unmapped(&mut self.wbuf, query, self.read_group.as_deref())
+            }
+        }
+    }
+    fn write_paf(&mut self, query: &Query, mapped: &Mapped) -> Result<()> {
+        let with_cigar = self.with_cigar;
+        let labels = self.labels.clone();
+        self.write_paf_records(
+            query,
+            mapped
+                .mappings
+                .iter()
+                .zip(&mapped.scores)
+                .map(|(alignment, scores)| {
+                    let mut record = MappingNutype::new(alignment, with_cigar);
+                    record.add_chain_scores(scores, mapped.repeat_len);
+                    record.label = labels
+                        .as_ref()
+                        .and_then(|labels| labels.label(alignment))
+                        .map(|label| format!("lb:Z:{label}"));
+                    record
+                }),
+        )
     }
-    fn write_local(&mut self, mapping: Vec<Mapping>) -> Result<()> {
+    fn write_paf_records<I>(&mut self, query: &Query, records: I) -> Result<()>
+    where
+        I: IntoIterator<Item = MappingNutype>,
+    {
+        // Records of one query may carry different tags (e.g. `s2` only on primary chains)
         let mut wtr = csv::WriterBuilder::new()
             .has_headers(false)
+            .flexible(true)
             .delimiter(b'\t')
             .from_writer(&mut self.wbuf);
 
-        for alignment in mapping {
-            let mapping = MappingNutype::new(alignment, self.with_cigar);
+        for mut mapping in records {
+            if let Some(mate) = &query.mate {
+                mapping.query_name = Arc::new(format!("{}{}", mapping.query_name, mate.suffix()));
+            }
+            mapping.read_group = self
+                .read_group
+                .as_ref()
+                .map(|read_group| format!("RG:Z:{read_group}"));
             wtr.serialize(mapping)?;
         }
         wtr.flush()?;
         Ok(())
     }
     fn write_record_set(&mut self) -> Result<()> {
-        // Open a thread-safe stdout writer
-        //
-        // Drops lock when it goes out of scope
+        for output in [&mut self.mapped_out, &mut self.unmapped_out]
+            .into_iter()
+            .flatten()
         {
-            let _lock = self.io_lock.lock();
-            let mut handle = self.reopen_handle()?;
-            handle.write_all(&self.wbuf)?;
-            handle.flush()?;
+            output.flush()?;
         }
+        let batch = self.batch.take();
 
-        // Clear the write buffer
-        self.wbuf.clear();
+        // Records are compressed before waiting for the preceding batches (sorted records once
+        // they are merged)
+        let buffer = if self.sorter.is_some() {
+            std::mem::replace(&mut self.wbuf, self.output.buffer())
+        } else if self.format == OutputFormat::Bam {
+            let mut cbuf = self.output.buffer();
+            bgzf::compress_blocks(&self.wbuf, Compression::default(), &mut cbuf)?;
+            self.wbuf.clear();
+            cbuf
+        } else {
+            let buffer = std::mem::replace(&mut self.wbuf, self.output.buffer());
+            self.output.compress(buffer)?
+        };
 
+        match (&self.order, batch) {
+            (Some(order), Some((start, end))) => {
+                order.submit(start, end, buffer, |data| self.write_output(data))
+            }
+            // Batches without records do not advance the input order
+            (Some(_), None) => {
+                self.output.recycle(buffer);
+                Ok(())
+            }
+            (None, _) => self.write_output(buffer),
+        }
+    }
+
+    /// Queues finished (compressed) records for the output (or adds them to the sorter)
+    fn write_output(&self, data: Vec<u8>) -> Result<()> {
+        let Some(sorter) = &self.sorter else {
+            return self.output.queue(data);
+        };
+        if let Some(sorter) = sorter.lock().as_mut() {
+            sorter.extend(&data)?;
+        }
+        self.output.recycle(data);
+        Ok(())
+    }
+
+    /// Finalizes the output once all records have been processed
+    ///
+    /// Closes the BGZF stream of BAM output, merges (and indexes) sorted output, and waits for
+    /// the writer thread to flush the output.
+    pub fn finish_output(&self) -> Result<()> {
+        let Some(sorter) = self.sorter.as_ref().and_then(|sorter| sorter.lock().take()) else {
+            if self.format == OutputFormat::Bam {
+                self.output.send(bgzf::EOF_BLOCK.to_vec())?;
+            }
+            return self.output.finish();
+        };
+
+        let mut writer = BgzfWriter::new(self.output.clone());
+        writer.write_data(&bam::encode_header(&self.aligner, &self.read_groups, true)?)?;
+        writer.close_block()?;
+
+        // Only file outputs can be indexed
+        let mut index = self.output_path.as_ref().map(|_| {
+            let lengths = sam::reference_sequences(&self.aligner)
+                .into_iter()
+                .map(|(_, len)| len)
+                .collect::<Vec<_>>();
+            IndexBuilder::new(&lengths)
+        });
+        sorter.merge_into(&mut writer, index.as_mut())?;
+        writer.finish()?.finish()?;
+
+        if let (Some(path), Some(index)) = (&self.output_path, index) {
+            index.write(&format!("{}.{}", path, index.extension()))?;
+        }
         Ok(())
     }
     fn calculate_throughput(&self) -> f64 {
@@ -140,6 +649,19 @@ impl ParallelAlignment {
     fn update_statistics(&mut self) {
         *self.global_n_processed.lock() += self.local_n_processed;
         self.local_n_processed = 0;
+        self.global_filter_stats
+            .lock()
+            .absorb(&mut self.local_filter_stats);
+        self.global_stats.lock().absorb(&mut self.local_stats);
+        if !self.local_label_reads.is_empty() {
+            let mut reads = self.global_label_reads.lock();
+            for (total, local) in reads.iter_mut().zip(&mut self.local_label_reads) {
+                *total += std::mem::take(local);
+            }
+        }
+        if let Some(counts) = &self.counts {
+            counts.absorb(&mut self.local_counts);
+        }
     }
     fn update_pbar(&self) {
         // only update progress bar on the main thread
@@ -164,24 +686,75 @@ impl ParallelAlignment {
     pub fn num_records(&self) -> usize {
         *self.global_n_processed.lock()
     }
+    pub fn sampling_summary(&self) -> Option<SamplingSummary> {
+        self.sampler.as_ref().map(|sampler| sampler.summary())
+    }
+    pub fn filter_summary(&self) -> Option<FilterStats> {
+        self.filter
+            .as_ref()
+            .map(|_| self.global_filter_stats.lock().clone())
+    }
+    pub fn reference_summary(&self) -> Option<Vec<LabelSummary>> {
+        self.labels
+            .as_ref()
+            .map(|labels| labels.summary(&self.global_label_reads.lock()))
+    }
+    pub fn mapping_summary(&self) -> MappingSummary {
+        self.global_stats.lock().summary()
+    }
+    pub fn index_options(&self) -> IdxOpt {
+        self.aligner.idxopt
+    }
 }
 impl binseq::ParallelProcessor for ParallelAlignment {
     fn process_record<B: BinseqRecord>(&mut self, record: B) -> binseq::Result<()> {
-        let query_name = format!("bq.{}", record.index());
+        if !self.sample_index(record.index()) {
+            return Ok(());
+        }
+        let mut query_name = std::mem::take(&mut self.nbuf);
+        self.naming.write(record.index(), &mut query_name);
+        let paired = record.is_paired();
         self.decode_record(record)?;
-        let mapping = match self.aligner.map(
-            &self.dbuf,
-            self.with_cigar,
-            false,
-            None,
-            None,
-            Some(query_name.as_bytes()),
-        ) {
-            Ok(mapping) => mapping,
-            Err(err) => return Err(anyhow!("Error mapping record: {}", err).into()),
-        };
+        if !self.passes_filters_decoded(paired) {
+            self.nbuf = query_name;
+            return Ok(());
+        }
         self.local_n_processed += 1;
-        self.write_local(mapping)?;
+
+        // Temporarily take the decode buffers to lend them to the writer
+        let seq = std::mem::take(&mut self.dbuf);
+        let qual = std::mem::take(&mut self.qbuf);
+        let query = Query {
+            name: &query_name,
+            seq: &seq,
+            qual: Some(&qual),
+            mate: None,
+        };
+        if paired {
+            let xseq = std::mem::take(&mut self.xbuf);
+            let xqual = std::mem::take(&mut self.xqbuf);
+            let mate = Query {
+                name: &query_name,
+                seq: &xseq,
+                qual: Some(&xqual),
+                mate: None,
+            };
+            self.map_pair(&query_name, query, mate)?;
+            self.xbuf = xseq;
+            self.xqbuf = xqual;
+        } else {
+            // Base-level alignment is enabled through the mapping options (`-c`), the
+            // `cs` argument only controls the difference string
+            let mapped = match mapper::map(&self.aligner, &seq, self.cs, self.md, &query_name) {
+                Ok(mapped) => mapped,
+                Err(err) => return Err(anyhow!("Error mapping record: {}", err).into()),
+            };
+            self.write_reads(&query, None, !mapped.is_empty())?;
+            self.write_local(query, &mapped)?;
+        }
+        self.dbuf = seq;
+        self.qbuf = qual;
+        self.nbuf = query_name;
         Ok(())
     }
 
@@ -198,18 +771,67 @@ impl binseq::ParallelProcessor for ParallelAlignment {
 }
 impl paraseq::parallel::ParallelProcessor for ParallelAlignment {
     fn process_record<Rf: Record>(&mut self, record: Rf) -> paraseq::parallel::Result<()> {
-        let mapping =
-            match self
-                .aligner
-                .map(&record.seq(), false, false, None, None, Some(record.id()))
-            {
-                Ok(mapping) => mapping,
-                Err(err) => {
-                    return Err(ProcessError::from(anyhow!("Error mapping record: {}", err)));
-                }
-            };
+        let seq = record.seq();
+        if !self.passes_filters((&seq, record.qual()), None) {
+            return Ok(());
+        }
+        let mapped = match mapper::map(&self.aligner, &seq, self.cs, self.md, record.id()) {
+            Ok(mapped) => mapped,
+            Err(err) => {
+                return Err(ProcessError::from(anyhow!("Error mapping record: {}", err)));
+            }
+        };
         self.local_n_processed += 1;
-        self.write_local(mapping)?;
+        let query = Query {
+            name: record.id(),
+            seq: &seq,
+            qual: record.qual(),
+            mate: None,
+        };
+        self.write_reads(&query, None, !mapped.is_empty())?;
+        self.write_local(query, &mapped)?;
+        Ok(())
+    }
+
+    fn on_batch_complete(&mut self) -> paraseq::parallel::Result<()> {
+        self.write_record_set()?;
+        self.update_statistics();
+        self.update_pbar();
+        Ok(())
+    }
+
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.tid = thread_id;
+    }
+}
+impl paraseq::parallel::PairedParallelProcessor for ParallelAlignment {
+    fn process_record_pair<Rf: Record>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+    ) -> paraseq::parallel::Result<()> {
+        self.process_fastq_pair(record1, record2)?;
+        Ok(())
+    }
+
+    fn on_batch_complete(&mut self) -> paraseq::parallel::Result<()> {
+        self.write_record_set()?;
+        self.update_statistics();
+        self.update_pbar();
+        Ok(())
+    }
+
+    fn set_thread_id(&mut self, thread_id: usize) {
+        self.tid = thread_id;
+    }
+}
+impl paraseq::parallel::InterleavedParallelProcessor for ParallelAlignment {
+    fn process_interleaved_pair<Rf: Record>(
+        &mut self,
+        record1: Rf,
+        record2: Rf,
+    ) -> paraseq::parallel::Result<()> {
+        self.process_fastq_pair(record1, record2)?;
         Ok(())
     }
 
@@ -239,14 +861,54 @@ pub struct MappingNutype {
     pub match_len: i32,
     pub block_len: i32,
     pub mapq: u32,
+
+    // Optional tags (in the order minimap2 writes them)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit_distance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_dp_score: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alignment_score: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n_ambiguous: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcript_strand: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alignment_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n_minimizers: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_score: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secondary_score: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divergence: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_divergence: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_len: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cigar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_group: Option<String>,
 }
 impl MappingNutype {
-    fn new(mapping: Mapping, with_cigar: bool) -> Self {
+    fn new(mapping: &Mapping, with_cigar: bool) -> Self {
+        let alignment = mapping.alignment.as_ref();
+
+        // Ambiguous bases are not exposed directly but are included in the edit distance
+        let n_ambiguous =
+            alignment.map(|alignment| alignment.nm - (mapping.block_len - mapping.match_len));
         Self {
             query_name: mapping
                 .query_name
+                .clone()
                 .unwrap_or_else(|| Arc::new("*".to_string())),
             query_len: mapping.query_len,
             query_start: mapping.query_start,
@@ -255,22 +917,309 @@ impl MappingNutype {
                 Strand::Forward => '+',
                 Strand::Reverse => '-',
             },
-            target_name: mapping.target_name,
+            target_name: mapping.target_name.clone(),
             target_len: mapping.target_len,
             target_start: mapping.target_start,
             target_end: mapping.target_end,
             match_len: mapping.match_len,
             block_len: mapping.block_len,
             mapq: mapping.mapq,
+            edit_distance: alignment.map(|alignment| format!("NM:i:{}", alignment.nm)),
+            max_dp_score: None,
+            alignment_score: alignment
+                .and_then(|alignment| alignment.alignment_score)
+                .map(|score| format!("AS:i:{score}")),
+            n_ambiguous: n_ambiguous.map(|n| format!("nn:i:{n}")),
+            transcript_strand: mapping.trans_strand.map(|strand| match strand {
+                Strand::Forward => "ts:A:+".to_string(),
+                Strand::Reverse => "ts:A:-".to_string(),
+            }),
+            alignment_type: Some(if mapping.is_primary || mapping.is_supplementary {
+                "tp:A:P".to_string()
+            } else {
+                "tp:A:S".to_string()
+            }),
+            n_minimizers: None,
+            chain_score: None,
+            secondary_score: None,
+            divergence: alignment
+                .zip(n_ambiguous)
+                .and_then(|(alignment, n_ambiguous)| {
+                    gap_compressed_divergence(mapping, alignment.cigar.as_deref()?, n_ambiguous)
+                })
+                .map(|divergence| format!("de:f:{}", format_divergence(divergence))),
+            chain_divergence: None,
+            repeat_len: None,
             cigar: if with_cigar {
-                if let Some(alignment) = mapping.alignment {
-                    alignment.cigar_str.map(|cigar| format!("cg:Z:{}M", cigar))
-                } else {
-                    Some(format!("cg:Z:{:?}M", mapping.query_len))
-                }
+                mapping
+                    .alignment
+                    .as_ref()
+                    .and_then(|alignment| alignment.cigar.as_deref())
+                    .filter(|ops| !ops.is_empty())
+                    .map(|ops| format!("cg:Z:{}", format_cigar(ops)))
             } else {
                 None
             },
+            cs: alignment
+                .and_then(|alignment| alignment.cs.as_deref())
+                .map(|cs| format!("cs:Z:{cs}")),
+            md: alignment
+                .and_then(|alignment| alignment.md.as_deref())
+                .map(|md| format!("MD:Z:{md}")),
+            label: None,
+            read_group: None,
+        }
+    }
+
+    /// Adds the chaining tags (`ms`, `cm`, `s1`, `s2`, `dv`, and `rl`)
+    ///
+    /// The chain divergence is only written for mappings without base-level alignment.
+    fn add_chain_scores(&mut self, scores: &ChainScores, repeat_len: i32) {
+        self.max_dp_score = scores.max_dp_score.map(|score| format!("ms:i:{score}"));
+        self.n_minimizers = Some(format!("cm:i:{}", scores.n_minimizers));
+        self.chain_score = Some(format!("s1:i:{}", scores.chain_score));
+        self.secondary_score = scores.secondary_score.map(|score| format!("s2:i:{score}"));
+        self.chain_divergence = (scores.max_dp_score.is_none()
+            && (0.0..=1.0).contains(&scores.divergence))
+        .then(|| format!("dv:f:{}", format_divergence(scores.divergence as f64)));
+        self.repeat_len = Some(format!("rl:i:{repeat_len}"));
+    }
+}
+
+impl MappingNutype {
+    /// Placeholder record of a query without hits (as minimap2's `--paf-no-hit`)
+    fn unmapped(query: &Query, repeat_len: i32) -> Self {
+        Self {
+            query_name: Arc::new(String::from_utf8_lossy(query.name).into_owned()),
+            query_len: NonZeroI32::new(query.seq.len() as i32),
+            query_start: 0,
+            query_end: 0,
+            strand: '*',
+            target_name: Some(Arc::new("*".to_string())),
+            target_len: 0,
+            target_start: 0,
+            target_end: 0,
+            match_len: 0,
+            block_len: 0,
+            mapq: 0,
+            edit_distance: None,
+            max_dp_score: None,
+            alignment_score: None,
+            n_ambiguous: None,
+            transcript_strand: None,
+            alignment_type: None,
+            n_minimizers: None,
+            chain_score: None,
+            secondary_score: None,
+            divergence: None,
+            chain_divergence: None,
+            repeat_len: Some(format!("rl:i:{repeat_len}")),
+            cigar: None,
+            cs: None,
+            md: None,
+            label: None,
+            read_group: None,
+        }
+    }
+}
+
+/// Gap-compressed divergence of an alignment (as `mm_event_identity` of minimap2)
+///
+/// Each gap counts as a single difference regardless of its length.
+fn gap_compressed_divergence(
+    mapping: &Mapping,
+    cigar: &[(u32, u8)],
+    n_ambiguous: i32,
+) -> Option<f64> {
+    let (n_gap, n_gap_open) = cigar
+        .iter()
+        .filter(|(_, op)| matches!(op, 1 | 2))
+        .fold((0, 0), |(n_gap, n_gap_open), (len, _)| {
+            (n_gap + *len as i32, n_gap_open + 1)
+        });
+    let denominator = mapping.block_len + n_ambiguous - n_gap + n_gap_open;
+    (denominator > 0).then(|| 1.0 - mapping.match_len as f64 / denominator as f64)
+}
+
+/// Formats a divergence as minimap2 (`%.4f`, or `0` without differences)
+fn format_divergence(divergence: f64) -> String {
+    if divergence == 0.0 {
+        "0".to_string()
+    } else {
+        format!("{divergence:.4}")
+    }
+}
+
+/// Formats CIGAR operations (without clipping) as in the `cg` tag of minimap2's PAF output
+fn format_cigar(ops: &[(u32, u8)]) -> String {
+    let mut cigar = String::with_capacity(ops.len() * 4);
+    for (len, op) in ops {
+        cigar.push_str(&len.to_string());
+        cigar.push(b"MIDNSHP=X"[*op as usize] as char);
+    }
+    cigar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use minimap2::Alignment;
+
+    fn mapping(alignment: Option<Alignment>) -> Mapping {
+        Mapping {
+            query_len: NonZeroI32::new(100),
+            query_end: 100,
+            target_len: 1000,
+            target_end: 100,
+            match_len: 98,
+            block_len: 101,
+            mapq: 60,
+            is_primary: true,
+            alignment,
+            ..Default::default()
+        }
+    }
+
+    fn alignment(cigar: Option<Vec<(u32, u8)>>) -> Alignment {
+        Alignment {
+            nm: 3,
+            cigar,
+            cigar_str: None,
+            md: None,
+            cs: None,
+            alignment_score: Some(180),
+        }
+    }
+
+    #[test]
+    fn divergence_format() {
+        // minimap2 prints `%.4f`, or `0` for identical sequences
+        assert_eq!(format_divergence(0.0), "0");
+        assert_eq!(format_divergence(0.00012), "0.0001");
+        assert_eq!(format_divergence(0.0124), "0.0124");
+        assert_eq!(format_divergence(0.123456), "0.1235");
+    }
+
+    #[test]
+    fn chain_score_tags() {
+        let scores = ChainScores {
+            n_minimizers: 23,
+            chain_score: 277,
+            secondary_score: Some(41),
+            max_dp_score: Some(190),
+            divergence: 0.0012,
+        };
+        let mut record = MappingNutype::new(&mapping(Some(alignment(Some(vec![(100, 0)])))), true);
+        record.add_chain_scores(&scores, 12);
+        assert_eq!(record.max_dp_score.as_deref(), Some("ms:i:190"));
+        assert_eq!(record.n_minimizers.as_deref(), Some("cm:i:23"));
+        assert_eq!(record.chain_score.as_deref(), Some("s1:i:277"));
+        assert_eq!(record.secondary_score.as_deref(), Some("s2:i:41"));
+        assert_eq!(record.repeat_len.as_deref(), Some("rl:i:12"));
+
+        // The chain divergence is replaced by the alignment divergence with base-level alignment
+        assert!(record.divergence.is_some());
+        assert_eq!(record.chain_divergence, None);
+        let mut record = MappingNutype::new(&mapping(None), false);
+        record.add_chain_scores(
+            &ChainScores {
+                secondary_score: None,
+                max_dp_score: None,
+                ..scores
+            },
+            0,
+        );
+        assert_eq!(record.chain_divergence.as_deref(), Some("dv:f:0.0012"));
+        assert_eq!(record.secondary_score, None);
+        assert_eq!(record.max_dp_score, None);
+    }
+
+    #[test]
+    fn format_cigar_ops() {
+        let ops = [(5, 4), (50, 0), (1, 1), (49, 0), (1, 2), (3, 4)];
+        assert_eq!(format_cigar(&ops), "5S50M1I49M1D3S");
+    }
+
+    /// Pseudo-random sequence (xorshift64), the same for every run
+    fn random_seq(mut state: u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGT"[(state >> 32) as usize % 4]
+            })
+            .collect()
+    }
+
+    /// Expected tags from `minimap2 -x sr -c` on the same reference and reads
+    #[test]
+    fn cigar_tag_matches_minimap2() {
+        let reference = random_seq(0x2545_f491_4f6c_dd1d, 2000);
+        let aligner = Aligner::builder()
+            .sr()
+            .with_cigar()
+            .with_seq(&reference)
+            .unwrap();
+
+        let insertion = [&reference[100..175], b"GA", &reference[175..250]].concat();
+        let deletion = [&reference[400..480], &reference[483..560]].concat();
+        let clipped = [&random_seq(0x9e37_79b9_7f4a_7c15, 20), &reference[800..930]].concat();
+        let mismatch = match reference[1240] {
+            b'A' => b'C',
+            b'C' => b'G',
+            b'G' => b'T',
+            _ => b'A',
+        };
+        let reverse: Vec<u8> = [
+            &reference[1200..1240],
+            &[mismatch],
+            &reference[1241..1280],
+            &reference[1282..1350],
+        ]
+        .concat()
+        .iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            _ => b'A',
+        })
+        .collect();
+
+        for (seq, query_start, expected) in [
+            (insertion, 0, "cg:Z:75M2I75M"),
+            (deletion, 0, "cg:Z:80M3D77M"),
+            (clipped, 19, "cg:Z:131M"),
+            (reverse, 0, "cg:Z:80M2D68M"),
+        ] {
+            let mapped = mapper::map(&aligner, &seq, None, false, b"read").unwrap();
+            assert_eq!(mapped.mappings.len(), 1);
+            let mapping = &mapped.mappings[0];
+            assert_eq!(mapping.query_start, query_start);
+            assert_eq!(
+                MappingNutype::new(mapping, true).cigar.as_deref(),
+                Some(expected)
+            );
         }
     }
+
+    #[test]
+    fn cigar_tag_omitted_without_cigar() {
+        assert_eq!(MappingNutype::new(&mapping(None), true).cigar, None);
+        assert_eq!(
+            MappingNutype::new(&mapping(Some(alignment(None))), true).cigar,
+            None
+        );
+        assert_eq!(
+            MappingNutype::new(&mapping(Some(alignment(Some(Vec::new())))), true).cigar,
+            None
+        );
+        assert_eq!(
+            MappingNutype::new(&mapping(Some(alignment(Some(vec![(100, 0)])))), false).cigar,
+            None
+        );
+    }
 }
@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use anyhow::{bail, Result};
+
+use super::{is_unmapped, record_end, record_position};
+use crate::bgzf::BgzfWriter;
+
+/// Minimum interval size (as a bit shift) of the binning scheme
+const MIN_SHIFT: u32 = 14;
+
+/// Depth of the BAI binning scheme
+const BAI_DEPTH: u32 = 5;
+
+/// Calculates the bin of a region `[beg, end)` for a binning scheme
+pub fn reg2bin(beg: i64, end: i64, min_shift: u32, depth: u32) -> u32 {
+    let end = end - 1;
+    let mut shift = min_shift;
+    let mut t = ((1 << (depth * 3)) - 1) / 7;
+    for level in (1..=depth).rev() {
+        if beg >> shift == end >> shift {
+            return t + (beg >> shift) as u32;
+        }
+        shift += 3;
+        t -= 1 << ((level - 1) * 3);
+    }
+    0
+}
+
+/// Index statistics of a single reference sequence
+#[derive(Default)]
+struct ReferenceIndex {
+    /// Chunks (virtual offset ranges) of each bin
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+
+    /// Smallest virtual offset of records overlapping each 16kbp window (BAI only)
+    linear: Vec<u64>,
+
+    /// Virtual offsets of the first and last record of the reference
+    offsets: Option<(u64, u64)>,
+
+    n_mapped: u64,
+    n_unmapped: u64,
+}
+
+/// Builds a BAI (or CSI for long references) index of a coordinate-sorted BAM file
+pub struct IndexBuilder {
+    references: Vec<ReferenceIndex>,
+
+    /// Depth of the binning scheme (5 for BAI)
+    depth: u32,
+
+    /// Whether to write a CSI index instead of a BAI index
+    csi: bool,
+
+    /// Number of records without coordinates
+    n_no_coor: u64,
+
+    /// Position of the previous record (to validate sort order)
+    last: (i32, i32),
+}
+impl IndexBuilder {
+    /// Creates an index builder for the given reference lengths
+    ///
+    /// A CSI index is used if any reference exceeds the BAI coordinate limit (2^29).
+    pub fn new(reference_lengths: &[u32]) -> Self {
+        let max_len = reference_lengths.iter().copied().max().unwrap_or(0) as u64;
+        let mut depth = BAI_DEPTH;
+        while (1u64 << (MIN_SHIFT + 3 * depth)) < max_len {
+            depth += 1;
+        }
+        Self {
+            references: (0..reference_lengths.len())
+                .map(|_| ReferenceIndex::default())
+                .collect(),
+            depth,
+            csi: depth > BAI_DEPTH,
+            n_no_coor: 0,
+            last: (0, -1),
+        }
+    }
+
+    /// File extension of the index
+    pub fn extension(&self) -> &'static str {
+        if self.csi {
+            "csi"
+        } else {
+            "bai"
+        }
+    }
+
+    /// Adds a record spanning the virtual offsets `[beg, end)` to the index
+    pub fn push(&mut self, record: &[u8], beg: u64, end: u64) -> Result<()> {
+        let (ref_id, pos) = record_position(record);
+        if ref_id < 0 {
+            self.n_no_coor += 1;
+            return Ok(());
+        }
+        if (ref_id as u32, pos) < (self.last.0 as u32, self.last.1) {
+            bail!("Unable to index BAM file: records are not coordinate sorted");
+        }
+        self.last = (ref_id, pos);
+
+        let reference = &mut self.references[ref_id as usize];
+        if is_unmapped(record) {
+            reference.n_unmapped += 1;
+        } else {
+            reference.n_mapped += 1;
+        }
+        reference.offsets = match reference.offsets {
+            Some((first, _)) => Some((first, end)),
+            None => Some((beg, end)),
+        };
+
+        // Record the chunk in the bin of the record
+        let rec_end = record_end(record);
+        let bin = reg2bin(pos as i64, rec_end as i64, MIN_SHIFT, self.depth);
+        let chunks = reference.bins.entry(bin).or_default();
+        match chunks.last_mut() {
+            Some(last) if last.1 == beg => last.1 = end,
+            _ => chunks.push((beg, end)),
+        }
+
+        // Update the linear index
+        if !self.csi {
+            let first = (pos >> MIN_SHIFT) as usize;
+            let last = ((rec_end - 1).max(pos) >> MIN_SHIFT) as usize;
+            if reference.linear.len() <= last {
+                reference.linear.resize(last + 1, 0);
+            }
+            for window in reference.linear[first..=last].iter_mut() {
+                if *window == 0 {
+                    *window = beg;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bin id of the pseudo-bin holding the reference statistics
+    fn pseudo_bin(&self) -> u32 {
+        ((1 << ((self.depth + 1) * 3)) - 1) / 7 + 1
+    }
+
+    fn write_references<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let pseudo_bin = self.pseudo_bin();
+        writer.write_all(&(self.references.len() as i32).to_le_bytes())?;
+        for reference in self.references.iter() {
+            let n_bin = reference.bins.len() + reference.offsets.is_some() as usize;
+            writer.write_all(&(n_bin as i32).to_le_bytes())?;
+            for (bin, chunks) in reference.bins.iter() {
+                writer.write_all(&bin.to_le_bytes())?;
+                if self.csi {
+                    // The smallest virtual offset of the bin serves as its loffset
+                    writer.write_all(&chunks[0].0.to_le_bytes())?;
+                }
+                writer.write_all(&(chunks.len() as i32).to_le_bytes())?;
+                for (beg, end) in chunks {
+                    writer.write_all(&beg.to_le_bytes())?;
+                    writer.write_all(&end.to_le_bytes())?;
+                }
+            }
+            if let Some((first, last)) = reference.offsets {
+                writer.write_all(&pseudo_bin.to_le_bytes())?;
+                if self.csi {
+                    writer.write_all(&0u64.to_le_bytes())?;
+                }
+                writer.write_all(&2i32.to_le_bytes())?;
+                writer.write_all(&first.to_le_bytes())?;
+                writer.write_all(&last.to_le_bytes())?;
+                writer.write_all(&reference.n_mapped.to_le_bytes())?;
+                writer.write_all(&reference.n_unmapped.to_le_bytes())?;
+            }
+            if !self.csi {
+                writer.write_all(&(reference.linear.len() as i32).to_le_bytes())?;
+                let mut previous = 0;
+                for offset in reference.linear.iter() {
+                    // Empty windows inherit the offset of the preceding window
+                    if *offset != 0 {
+                        previous = *offset;
+                    }
+                    writer.write_all(&previous.to_le_bytes())?;
+                }
+            }
+        }
+        writer.write_all(&self.n_no_coor.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Writes the index to the provided path
+    pub fn write(&self, path: &str) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        if self.csi {
+            let mut body = Vec::new();
+            body.extend_from_slice(b"CSI\x01");
+            body.extend_from_slice(&(MIN_SHIFT as i32).to_le_bytes());
+            body.extend_from_slice(&(self.depth as i32).to_le_bytes());
+            body.extend_from_slice(&0i32.to_le_bytes()); // l_aux
+            self.write_references(&mut body)?;
+
+            let mut writer = BgzfWriter::new(file);
+            writer.write_data(&body)?;
+            writer.finish()?;
+        } else {
+            let mut writer = file;
+            writer.write_all(b"BAI\x01")?;
+            self.write_references(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+mod index;
+mod sort;
+
+pub use index::IndexBuilder;
+pub use sort::BamSorter;
+
+use anyhow::{bail, Result};
+use minimap2::{Aligner, Built, Mapping};
+
+use crate::reference::ReferenceLabels;
+use crate::sam::{self, Query};
+
+/// Size of the fixed-length portion of a BAM record (excluding `block_size`)
+const FIXED_SIZE: usize = 32;
+
+/// Maximum number of CIGAR operations stored in the CIGAR field of a BAM record
+const MAX_CIGAR_OPS: usize = u16::MAX as usize;
+
+/// Encodes the BAM header (magic, SAM header text, and reference dictionary)
+pub fn encode_header(
+    aligner: &Aligner<Built>,
+    read_groups: &[String],
+    sorted: bool,
+) -> Result<Vec<u8>> {
+    let mut text = Vec::new();
+    sam::write_header(&mut text, aligner, read_groups, sorted)?;
+
+    let mut buf = Vec::with_capacity(text.len() + 1024);
+    buf.extend_from_slice(b"BAM\x01");
+    buf.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    buf.extend_from_slice(&text);
+
+    let references = sam::reference_sequences(aligner);
+    buf.extend_from_slice(&(references.len() as i32).to_le_bytes());
+    for (name, len) in references {
+        buf.extend_from_slice(&(name.len() as i32 + 1).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&(len as i32).to_le_bytes());
+    }
+    Ok(buf)
+}
+
+/// Encodes one BAM record per mapping of the query
+///
+/// Records are tagged with the label of their reference and the read group if provided.
+pub fn encode_records(
+    buf: &mut Vec<u8>,
+    query: &Query,
+    mappings: &[Mapping],
+    labels: Option<&ReferenceLabels>,
+    read_group: Option<&str>,
+) -> Result<()> {
+    for mapping in mappings {
+        let label = labels.and_then(|labels| labels.label(mapping));
+        encode_record(buf, query, mapping, label, read_group)?;
+    }
+    Ok(())
+}
+
+fn encode_record(
+    buf: &mut Vec<u8>,
+    query: &Query,
+    mapping: &Mapping,
+    label: Option<&str>,
+    read_group: Option<&str>,
+) -> Result<()> {
+    let flag = sam::sam_flag(query, mapping);
+    let name = sam::query_name(query.name);
+    if name.len() > 254 {
+        bail!(
+            "Query name is too long for BAM output: {}",
+            String::from_utf8_lossy(name)
+        );
+    }
+    let (seq, qual) = sam::oriented_seq_qual(query, flag);
+
+    let mut cigar = match mapping
+        .alignment
+        .as_ref()
+        .and_then(|aln| aln.cigar_str.as_deref())
+    {
+        Some(cigar) => parse_cigar(cigar)?,
+        None => Vec::new(),
+    };
+    let pos = mapping.target_start;
+    let end = if cigar.is_empty() {
+        mapping.target_end
+    } else {
+        pos + reference_length(&cigar) as i32
+    };
+
+    // CIGARs with too many operations are moved to the `CG` tag
+    let long_cigar = if cigar.len() > MAX_CIGAR_OPS {
+        let ops = std::mem::replace(
+            &mut cigar,
+            vec![
+                encode_op(query.seq.len() as u32, b'S')?,
+                encode_op((end - pos) as u32, b'N')?,
+            ],
+        );
+        Some(ops)
+    } else {
+        None
+    };
+
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]); // block_size placeholder
+    buf.extend_from_slice(&mapping.target_id.to_le_bytes());
+    buf.extend_from_slice(&pos.to_le_bytes());
+    buf.push(name.len() as u8 + 1);
+    buf.push(mapping.mapq.min(255) as u8);
+    buf.extend_from_slice(&reg2bin(pos as i64, end as i64).to_le_bytes());
+    buf.extend_from_slice(&(cigar.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&flag.to_le_bytes());
+    buf.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    let (next_ref_id, next_pos, tlen) = match sam::mate_fields(query, mapping) {
+        Some((mate, tlen)) => (mate.target_id, mate.target_start, tlen),
+        None => (-1, -1, 0),
+    };
+    buf.extend_from_slice(&next_ref_id.to_le_bytes());
+    buf.extend_from_slice(&next_pos.to_le_bytes());
+    buf.extend_from_slice(&tlen.to_le_bytes());
+
+    buf.extend_from_slice(name);
+    buf.push(0);
+    for op in &cigar {
+        buf.extend_from_slice(&op.to_le_bytes());
+    }
+    encode_seq(buf, &seq);
+    match qual {
+        Some(qual) => buf.extend(qual.iter().map(|q| q.saturating_sub(33))),
+        None => buf.extend(std::iter::repeat_n(0xff, seq.len())),
+    }
+
+    // Optional tags
+    if let Some(alignment) = &mapping.alignment {
+        buf.extend_from_slice(b"NMi");
+        buf.extend_from_slice(&alignment.nm.to_le_bytes());
+        if let Some(score) = alignment.alignment_score {
+            buf.extend_from_slice(b"ASi");
+            buf.extend_from_slice(&score.to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(b"tpA");
+    buf.push(if mapping.is_primary || mapping.is_supplementary {
+        b'P'
+    } else {
+        b'S'
+    });
+    if let Some(alignment) = &mapping.alignment {
+        if let Some(cs) = &alignment.cs {
+            buf.extend_from_slice(b"csZ");
+            buf.extend_from_slice(cs.as_bytes());
+            buf.push(0);
+        }
+        if let Some(md) = &alignment.md {
+            buf.extend_from_slice(b"MDZ");
+            buf.extend_from_slice(md.as_bytes());
+            buf.push(0);
+        }
+    }
+    if let Some(label) = label {
+        buf.extend_from_slice(b"lbZ");
+        buf.extend_from_slice(label.as_bytes());
+        buf.push(0);
+    }
+    if let Some(read_group) = read_group {
+        buf.extend_from_slice(b"RGZ");
+        buf.extend_from_slice(read_group.as_bytes());
+        buf.push(0);
+    }
+    if let Some(ops) = long_cigar {
+        buf.extend_from_slice(b"CGBI");
+        buf.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        for op in ops {
+            buf.extend_from_slice(&op.to_le_bytes());
+        }
+    }
+
+    let block_size = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&block_size.to_le_bytes());
+    Ok(())
+}
+
+/// Encodes an unmapped BAM record for a query without hits
+///
+/// The record is placed at the primary mapping of its mate (if any).
+pub fn encode_unmapped(buf: &mut Vec<u8>, query: &Query, read_group: Option<&str>) -> Result<()> {
+    let flag = sam::unmapped_flag(query);
+    let name = sam::query_name(query.name);
+    if name.len() > 254 {
+        bail!(
+            "Query name is too long for BAM output: {}",
+            String::from_utf8_lossy(name)
+        );
+    }
+    let (seq, qual) = sam::oriented_seq_qual(query, flag);
+    let (ref_id, pos) = match query.mate.and_then(|mate| mate.primary) {
+        Some(mate) => (mate.target_id, mate.target_start),
+        None => (-1, -1),
+    };
+
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]); // block_size placeholder
+    buf.extend_from_slice(&ref_id.to_le_bytes());
+    buf.extend_from_slice(&pos.to_le_bytes());
+    buf.push(name.len() as u8 + 1);
+    buf.push(0); // mapq
+
+    // Records without coordinates are placed in bin 4680 (as htslib does)
+    let bin = if pos < 0 {
+        4680
+    } else {
+        reg2bin(pos as i64, pos as i64 + 1)
+    };
+    buf.extend_from_slice(&bin.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+    buf.extend_from_slice(&flag.to_le_bytes());
+    buf.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&ref_id.to_le_bytes());
+    buf.extend_from_slice(&pos.to_le_bytes());
+    buf.extend_from_slice(&0i32.to_le_bytes()); // tlen
+
+    buf.extend_from_slice(name);
+    buf.push(0);
+    encode_seq(buf, &seq);
+    match qual {
+        Some(qual) => buf.extend(qual.iter().map(|q| q.saturating_sub(33))),
+        None => buf.extend(std::iter::repeat_n(0xff, seq.len())),
+    }
+    if let Some(read_group) = read_group {
+        buf.extend_from_slice(b"RGZ");
+        buf.extend_from_slice(read_group.as_bytes());
+        buf.push(0);
+    }
+
+    let block_size = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&block_size.to_le_bytes());
+    Ok(())
+}
+
+fn encode_op(len: u32, op: u8) -> Result<u32> {
+    let code = match op {
+        b'M' => 0,
+        b'I' => 1,
+        b'D' => 2,
+        b'N' => 3,
+        b'S' => 4,
+        b'H' => 5,
+        b'P' => 6,
+        b'=' => 7,
+        b'X' => 8,
+        _ => bail!("Invalid CIGAR operation: {}", op as char),
+    };
+    Ok((len << 4) | code)
+}
+
+/// Parses a CIGAR string into BAM-encoded operations
+fn parse_cigar(cigar: &str) -> Result<Vec<u32>> {
+    let mut ops = Vec::new();
+    let mut len = 0u32;
+    for c in cigar.bytes() {
+        if c.is_ascii_digit() {
+            len = len * 10 + (c - b'0') as u32;
+        } else {
+            ops.push(encode_op(len, c)?);
+            len = 0;
+        }
+    }
+    Ok(ops)
+}
+
+/// Number of reference bases consumed by BAM-encoded CIGAR operations
+fn reference_length(cigar: &[u32]) -> u32 {
+    cigar
+        .iter()
+        .filter(|op| matches!(*op & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|op| op >> 4)
+        .sum()
+}
+
+/// Packs a nucleotide sequence into 4-bit BAM encoding
+fn encode_seq(buf: &mut Vec<u8>, seq: &[u8]) {
+    fn code(base: u8) -> u8 {
+        match base.to_ascii_uppercase() {
+            b'=' => 0,
+            b'A' => 1,
+            b'C' => 2,
+            b'M' => 3,
+            b'G' => 4,
+            b'R' => 5,
+            b'S' => 6,
+            b'V' => 7,
+            b'T' => 8,
+            b'W' => 9,
+            b'Y' => 10,
+            b'H' => 11,
+            b'K' => 12,
+            b'D' => 13,
+            b'B' => 14,
+            _ => 15,
+        }
+    }
+    for pair in seq.chunks(2) {
+        let hi = code(pair[0]) << 4;
+        let lo = pair.get(1).map_or(0, |base| code(*base));
+        buf.push(hi | lo);
+    }
+}
+
+/// Calculates the BAI bin of a record spanning `[beg, end)`
+///
+/// Records extending beyond the BAI coordinate limit are placed in bin 4680 (as htslib does).
+fn reg2bin(beg: i64, end: i64) -> u16 {
+    if end > 1 << 29 {
+        4680
+    } else {
+        index::reg2bin(beg, end, 14, 5) as u16
+    }
+}
+
+/// Whether the BAM-encoded record is unmapped
+fn is_unmapped(record: &[u8]) -> bool {
+    let flag = u16::from_le_bytes([record[18], record[19]]);
+    flag & sam::FLAG_UNMAPPED != 0
+}
+
+/// Reads the reference id and position of a BAM-encoded record (including `block_size`)
+fn record_position(record: &[u8]) -> (i32, i32) {
+    let ref_id = i32::from_le_bytes(record[4..8].try_into().unwrap());
+    let pos = i32::from_le_bytes(record[8..12].try_into().unwrap());
+    (ref_id, pos)
+}
+
+/// Calculates the end position of a BAM-encoded record (including `block_size`)
+fn record_end(record: &[u8]) -> i32 {
+    let (_, pos) = record_position(record);
+    let l_read_name = record[12] as usize;
+    let n_cigar = u16::from_le_bytes([record[16], record[17]]) as usize;
+    let offset = 4 + FIXED_SIZE + l_read_name;
+    let cigar = record[offset..offset + 4 * n_cigar]
+        .chunks_exact(4)
+        .map(|op| u32::from_le_bytes(op.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let len = reference_length(&cigar) as i32;
+    if len == 0 {
+        pos + 1
+    } else {
+        pos + len
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, Result};
+
+use super::{record_position, IndexBuilder};
+use crate::bgzf::BgzfWriter;
+
+/// Counter used to create unique spill file names
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Coordinate sort key of a BAM record
+///
+/// Unmapped records (reference id of -1) are sorted to the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SortKey {
+    ref_id: u32,
+    pos: i32,
+    reverse: bool,
+}
+impl SortKey {
+    fn from_record(record: &[u8]) -> Self {
+        let (ref_id, pos) = record_position(record);
+        let flag = u16::from_le_bytes([record[18], record[19]]);
+        Self {
+            ref_id: ref_id as u32,
+            pos,
+            reverse: flag & 0x10 != 0,
+        }
+    }
+}
+
+/// External coordinate sorter for BAM records
+///
+/// Records are buffered in memory until the memory limit is reached, at which point they are
+/// sorted and spilled to a temporary file. The sorted runs are merged when the output is written.
+pub struct BamSorter {
+    /// Concatenated BAM records (including `block_size`)
+    records: Vec<u8>,
+
+    /// Sort key, offset, and length of each buffered record
+    keys: Vec<(SortKey, usize, usize)>,
+
+    /// Paths of the sorted runs on disk
+    runs: Vec<PathBuf>,
+
+    /// Directory to write the sorted runs to
+    tmp_dir: PathBuf,
+
+    /// Maximum number of bytes to buffer before spilling
+    memory_limit: usize,
+}
+impl BamSorter {
+    pub fn new(tmp_dir: PathBuf, memory_limit: usize) -> Self {
+        Self {
+            records: Vec::new(),
+            keys: Vec::new(),
+            runs: Vec::new(),
+            tmp_dir,
+            memory_limit,
+        }
+    }
+
+    /// Adds a buffer of concatenated BAM records to the sorter
+    pub fn extend(&mut self, data: &[u8]) -> Result<()> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let block_size = u32::from_le_bytes(data[offset..offset + 4].try_into()?) as usize;
+            let record = &data[offset..offset + 4 + block_size];
+            self.keys.push((
+                SortKey::from_record(record),
+                self.records.len(),
+                record.len(),
+            ));
+            self.records.extend_from_slice(record);
+            offset += record.len();
+        }
+        if self.records.len() >= self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Sorts the buffered records and writes them to a temporary file
+    fn spill(&mut self) -> Result<()> {
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+        self.keys.sort_by_key(|(key, _, _)| *key);
+
+        let path = self.tmp_dir.join(format!(
+            "mmr.{}.{}.tmp",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for (_, offset, len) in self.keys.iter() {
+            writer.write_all(&self.records[*offset..*offset + *len])?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+
+        self.records.clear();
+        self.keys.clear();
+        Ok(())
+    }
+
+    /// Merges all sorted runs into the BGZF writer, optionally indexing the records
+    pub fn merge_into<W: Write>(
+        mut self,
+        writer: &mut BgzfWriter<W>,
+        mut index: Option<&mut IndexBuilder>,
+    ) -> Result<()> {
+        self.spill()?;
+
+        let mut readers = self
+            .runs
+            .iter()
+            .map(|path| Ok(BufReader::new(File::open(path)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut current = vec![Vec::new(); readers.len()];
+
+        // Initialize the heap with the first record of each run
+        let mut heap = BinaryHeap::new();
+        for (run, reader) in readers.iter_mut().enumerate() {
+            if read_record(reader, &mut current[run])? {
+                heap.push(Reverse((SortKey::from_record(&current[run]), run)));
+            }
+        }
+
+        while let Some(Reverse((_, run))) = heap.pop() {
+            let beg = writer.virtual_offset();
+            writer.write_data(&current[run])?;
+            if let Some(index) = index.as_deref_mut() {
+                index.push(&current[run], beg, writer.virtual_offset())?;
+            }
+            if read_record(&mut readers[run], &mut current[run])? {
+                heap.push(Reverse((SortKey::from_record(&current[run]), run)));
+            }
+        }
+        Ok(())
+    }
+}
+impl Drop for BamSorter {
+    fn drop(&mut self) {
+        for path in self.runs.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Reads the next BAM record (including `block_size`) of a sorted run
+fn read_record<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<bool> {
+    buf.clear();
+    let mut size = [0u8; 4];
+    match reader.read_exact(&mut size) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(err) => bail!("Error reading sorted run: {}", err),
+    }
+    let block_size = u32::from_le_bytes(size) as usize;
+    buf.extend_from_slice(&size);
+    buf.resize(4 + block_size, 0);
+    reader.read_exact(&mut buf[4..])?;
+    Ok(true)
+}
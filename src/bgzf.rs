@@ -0,0 +1,131 @@
+use std::io::Write;
+
+use anyhow::Result;
+use flate2::{Compress, Compression, Crc, FlushCompress};
+
+/// Maximum number of uncompressed bytes stored in a single BGZF block
+pub const MAX_BLOCK_SIZE: usize = 0xff00;
+
+/// Size of the BGZF block header
+const HEADER_SIZE: usize = 18;
+
+/// The empty block marking the end of a BGZF file
+pub const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses a single chunk (at most `MAX_BLOCK_SIZE` bytes) into a BGZF block
+pub fn compress_block(data: &[u8], level: Compression, out: &mut Vec<u8>) -> Result<()> {
+    debug_assert!(data.len() <= MAX_BLOCK_SIZE);
+
+    let start = out.len();
+    out.extend_from_slice(&[
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02,
+        0x00, 0x00, 0x00,
+    ]);
+
+    // Deflate the data - falling back to stored blocks if it does not compress
+    let mut compressor = Compress::new(level, false);
+    out.reserve(data.len() + 64);
+    compressor.compress_vec(data, out, FlushCompress::Finish)?;
+    if compressor.total_in() as usize != data.len() || out.len() - start > u16::MAX as usize {
+        out.truncate(start + HEADER_SIZE);
+        let mut compressor = Compress::new(Compression::none(), false);
+        out.reserve(data.len() + 64);
+        compressor.compress_vec(data, out, FlushCompress::Finish)?;
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    out.extend_from_slice(&crc.sum().to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    // BSIZE is the total block size minus one
+    let block_size = (out.len() - start - 1) as u16;
+    out[start + 16..start + 18].copy_from_slice(&block_size.to_le_bytes());
+    Ok(())
+}
+
+/// Compresses an arbitrary buffer into a series of BGZF blocks
+pub fn compress_blocks(data: &[u8], level: Compression, out: &mut Vec<u8>) -> Result<()> {
+    for chunk in data.chunks(MAX_BLOCK_SIZE) {
+        compress_block(chunk, level, out)?;
+    }
+    Ok(())
+}
+
+/// A BGZF writer which keeps track of virtual file offsets
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+
+    /// Uncompressed data of the current block
+    buffer: Vec<u8>,
+
+    /// Compressed data of the current block
+    cbuf: Vec<u8>,
+
+    /// Number of compressed bytes written to the inner writer
+    compressed_offset: u64,
+
+    /// Compression level
+    level: Compression,
+}
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(MAX_BLOCK_SIZE),
+            cbuf: Vec::with_capacity(u16::MAX as usize + 1),
+            compressed_offset: 0,
+            level: Compression::default(),
+        }
+    }
+
+    /// The virtual offset of the next byte written
+    ///
+    /// The upper 48 bits hold the compressed offset of the current block and
+    /// the lower 16 bits hold the offset within the uncompressed block.
+    pub fn virtual_offset(&self) -> u64 {
+        (self.compressed_offset << 16) | self.buffer.len() as u64
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.cbuf.clear();
+        compress_block(&self.buffer, self.level, &mut self.cbuf)?;
+        self.inner.write_all(&self.cbuf)?;
+        self.compressed_offset += self.cbuf.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes data into the current block, emitting blocks as they fill up
+    pub fn write_data(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let available = MAX_BLOCK_SIZE - self.buffer.len();
+            let (head, tail) = data.split_at(available.min(data.len()));
+            self.buffer.extend_from_slice(head);
+            if self.buffer.len() == MAX_BLOCK_SIZE {
+                self.flush_block()?;
+            }
+            data = tail;
+        }
+        Ok(())
+    }
+
+    /// Closes the current block so the next write starts a new one
+    pub fn close_block(&mut self) -> Result<()> {
+        self.flush_block()
+    }
+
+    /// Flushes all pending data, writes the EOF marker, and returns the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        self.flush_block()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
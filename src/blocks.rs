@@ -0,0 +1,136 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{bail, Result};
+use binseq::vbq::{BlockRange, RefRecord, VBinseqHeader};
+
+/// Size of the header preceding each block of a VBQ file
+const SIZE_BLOCK_HEADER: u64 = 32;
+
+/// Size of the flag and the two sequence lengths preceding each record of a block
+const SIZE_RECORD_HEADER: usize = 24;
+
+/// Block of a VBQ file, read by the calling thread and decoded by a mapping thread
+///
+/// binseq only decodes blocks within its own parallel reader, so the (compressed) block is
+/// copied from the file as is and parsed here with the record layout of binseq.
+#[derive(Default)]
+pub struct VbqBlock {
+    /// Index of the first record of the block
+    index: u64,
+
+    compressed: bool,
+    has_quality: bool,
+
+    /// Block as stored in the file
+    raw: Vec<u8>,
+
+    /// Decompressed block
+    data: Vec<u8>,
+
+    flags: Vec<u64>,
+
+    /// Lengths of the primary and extended sequence of each record
+    lens: Vec<(u64, u64)>,
+
+    /// 2-bit encoded sequences of all records
+    sequences: Vec<u64>,
+
+    qualities: Vec<u8>,
+}
+impl VbqBlock {
+    /// Copies a block (located through the block index) from the file
+    pub fn read(
+        &mut self,
+        file: &mut File,
+        header: &VBinseqHeader,
+        range: &BlockRange,
+    ) -> Result<()> {
+        self.index = range.cumulative_records as u64;
+        self.compressed = header.compressed;
+        self.has_quality = header.qual;
+        self.raw.resize(range.len as usize, 0);
+        file.seek(SeekFrom::Start(range.start_offset + SIZE_BLOCK_HEADER))?;
+        file.read_exact(&mut self.raw)?;
+        Ok(())
+    }
+
+    /// Decompresses the block and splits it into records
+    pub fn decode(&mut self) -> Result<()> {
+        self.flags.clear();
+        self.lens.clear();
+        self.sequences.clear();
+        self.qualities.clear();
+        let data = if self.compressed {
+            self.data.clear();
+            zstd::stream::copy_decode(self.raw.as_slice(), &mut self.data)?;
+            &self.data
+        } else {
+            &self.raw
+        };
+
+        // Blocks are padded with zeros after the last record
+        let mut pos = 0;
+        while pos + SIZE_RECORD_HEADER <= data.len() {
+            let flag = read_u64(data, pos);
+            let slen = read_u64(data, pos + 8);
+            let xlen = read_u64(data, pos + 16);
+            pos += SIZE_RECORD_HEADER;
+            if slen == 0 {
+                break;
+            }
+            self.flags.push(flag);
+            self.lens.push((slen, xlen));
+            for len in [slen as usize, xlen as usize] {
+                let seq_end = pos + len.div_ceil(32) * 8;
+                let end = if self.has_quality {
+                    seq_end + len
+                } else {
+                    seq_end
+                };
+                if end > data.len() {
+                    bail!("Truncated record in VBQ block");
+                }
+                self.sequences.extend(
+                    data[pos..seq_end]
+                        .chunks_exact(8)
+                        .map(|word| u64::from_le_bytes(word.try_into().expect("8-byte word"))),
+                );
+                self.qualities.extend_from_slice(&data[seq_end..end]);
+                pos = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates over the decoded records
+    pub fn records(&self) -> impl Iterator<Item = RefRecord<'_>> {
+        let mut seq_pos = 0;
+        let mut qual_pos = 0;
+        self.flags.iter().zip(&self.lens).zip(self.index..).map(
+            move |((&flag, &(slen, xlen)), index)| {
+                let mut next = |len: u64| {
+                    let n_words = (len as usize).div_ceil(32);
+                    let seq = &self.sequences[seq_pos..seq_pos + n_words];
+                    seq_pos += n_words;
+                    let qual = if self.has_quality {
+                        qual_pos += len as usize;
+                        &self.qualities[qual_pos - len as usize..qual_pos]
+                    } else {
+                        &[]
+                    };
+                    (seq, qual)
+                };
+                let (sbuf, squal) = next(slen);
+                let (xbuf, xqual) = next(xlen);
+                RefRecord::new(index, flag, slen, xlen, sbuf, xbuf, squal, xqual)
+            },
+        )
+    }
+}
+
+fn read_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().expect("8-byte word"))
+}
@@ -3,7 +3,7 @@ use clap::{
         styling::{AnsiColor, Effects},
         Styles,
     },
-    Parser,
+    Parser, Subcommand,
 };
 
 // Configures Clap v3-style help menu colors
@@ -16,8 +16,16 @@ const STYLES: Styles = Styles::styled()
 use super::{IndexOptions, IoOptions, MappingOptions, RunOptions};
 
 #[derive(Parser)]
-#[command(styles = STYLES, version)]
+#[command(
+    styles = STYLES,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(flatten)]
     pub io_options: IoOptions,
 
@@ -30,3 +38,35 @@ pub struct Cli {
     #[clap(flatten)]
     pub mapping_options: MappingOptions,
 }
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Build a minimap2 index (.mmi) that can be reused across runs
+    Index(IndexCommand),
+}
+
+#[derive(Parser)]
+pub struct IndexCommand {
+    #[clap(help = "Path to the reference file to index")]
+    pub reference_path: String,
+
+    #[clap(
+        short,
+        long,
+        help = "Path to the output index [default: <reference>.mmi]"
+    )]
+    pub output_path: Option<String>,
+
+    #[clap(flatten)]
+    pub run_options: RunOptions,
+
+    #[clap(flatten)]
+    pub index_options: IndexOptions,
+}
+impl IndexCommand {
+    pub fn output_path(&self) -> String {
+        self.output_path
+            .clone()
+            .unwrap_or_else(|| format!("{}.mmi", self.reference_path))
+    }
+}
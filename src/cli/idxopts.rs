@@ -5,18 +5,23 @@ use super::PresetWrapper;
 #[derive(Parser, Clone, Copy)]
 #[clap(next_help_heading = "INDEX OPTIONS")]
 pub struct IndexOptions {
-    #[clap(short, long, help = "k-mer size (no larger than 28) [default: 15]")]
-    pub kmer_size: Option<i16>,
-
     #[clap(
         short,
         long,
-        default_value = "10",
-        help = "minimizer window size [default: 10]"
+        help = "k-mer size (no larger than 28) [default: set by preset]"
     )]
+    pub kmer_size: Option<i16>,
+
+    #[clap(short, long, help = "minimizer window size [default: set by preset]")]
     pub window_size: Option<i16>,
 
     /// Preset to use when aligning reads
-    #[clap(short = 'x', long)]
-    pub preset: PresetWrapper,
+    #[clap(short = 'x', long, required = true)]
+    preset: Option<PresetWrapper>,
+}
+impl IndexOptions {
+    pub fn preset(&self) -> PresetWrapper {
+        self.preset
+            .expect("preset is required without a subcommand")
+    }
 }
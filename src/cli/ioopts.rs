@@ -1,12 +1,415 @@
-use clap::Parser;
+use std::ops::Range;
+
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser)]
 #[clap(next_help_heading = "INPUT FILE OPTIONS")]
 pub struct IoOptions {
-    #[clap(help = "Path to the file to index")]
-    pub index_path: String,
-    #[clap(help = "Path to the binseq file to query")]
-    pub query_path: String,
+    #[clap(
+        required = true,
+        help = "Path to the file to index (FASTA or prebuilt .mmi), or comma-separated [label=]path FASTA files to index together"
+    )]
+    index_path: Option<String>,
+    #[clap(
+        required = true,
+        help = "Path to the query file (BINSEQ, FASTQ, or FASTA)"
+    )]
+    query_path: Option<String>,
+    #[clap(help = "Path to the second mate (R2) of paired FASTQ input")]
+    pub mate_path: Option<String>,
+    #[clap(
+        long,
+        conflicts_with = "query_path",
+        help = "Path to a manifest of query files (one path, or a tab-separated R1/R2 pair, per line)"
+    )]
+    pub manifest: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Format of the query file [default: detected from its contents]"
+    )]
+    pub input_format: Option<InputFormat>,
+
+    #[clap(
+        long,
+        conflicts_with = "mate_path",
+        help = "Treat the FASTQ input as interleaved mate pairs"
+    )]
+    pub interleaved: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "manifest",
+        help = "Path to the original read names of a BINSEQ query (one per line) [default: <query>.names if present]"
+    )]
+    pub names: Option<String>,
+
+    #[clap(
+        long,
+        help = "Name of BINSEQ records with {sample}, {index}, and {name} placeholders [default: {name} with read names, bq.{index} otherwise]"
+    )]
+    pub name_template: Option<String>,
+
+    #[clap(
+        long,
+        value_parser = parse_range,
+        conflicts_with = "manifest",
+        help = "Only map the BINSEQ records with indices in START..END (end exclusive)"
+    )]
+    pub range: Option<Range<usize>>,
+
+    #[clap(
+        long,
+        value_parser = parse_shard,
+        conflicts_with_all = ["manifest", "range"],
+        help = "Only map shard I of N equal slices of the BINSEQ records (I/N, with 0 <= I < N)"
+    )]
+    pub shard: Option<(usize, usize)>,
+
+    #[clap(
+        long,
+        value_parser = parse_fraction,
+        help = "Map a random fraction (0-1] of the query records (pairs are kept together)"
+    )]
+    pub subsample: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Map at most this many query records of each input (pairs count once)"
+    )]
+    pub max_reads: Option<u64>,
+
+    #[clap(
+        long,
+        default_value = "0",
+        requires = "subsample",
+        help = "Seed of the random subsampling (--subsample)"
+    )]
+    pub seed: u64,
+
+    #[clap(
+        long,
+        help = "Skip reads shorter than this length (either mate for pairs)"
+    )]
+    pub min_len: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Skip reads longer than this length (either mate for pairs)"
+    )]
+    pub max_len: Option<usize>,
+
+    #[clap(
+        long,
+        value_parser = parse_n_frac,
+        help = "Skip reads with a larger fraction of N bases (either mate for pairs)"
+    )]
+    pub max_n_frac: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Skip reads with a lower mean quality, from the mean error probability (either mate for pairs)"
+    )]
+    pub min_mean_q: Option<f64>,
+
     #[clap(short, long, help = "Path to the output file [default: stdout]")]
     pub output_path: Option<String>,
+
+    #[clap(
+        long,
+        conflicts_with = "output_path",
+        help = "Write one output file per query input into this directory (named after the input)"
+    )]
+    pub output_dir: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "paf",
+        help = "Output format of the alignments"
+    )]
+    pub output_format: OutputFormat,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Compression of the output [default: detected from the output path extension]"
+    )]
+    pub output_compression: Option<OutputCompression>,
+
+    #[clap(
+        short = 'a',
+        help = "Output in the SAM format (same as --output-format sam)"
+    )]
+    pub sam: bool,
+
+    #[clap(
+        long,
+        help = "Write a placeholder record (PAF row or unmapped SAM record) for queries without hits"
+    )]
+    pub report_unmapped: bool,
+
+    #[clap(
+        long,
+        help = "Write the query records with at least one mapping to this file (.bq, .vbq, or FASTQ/FASTA)"
+    )]
+    pub mapped_out: Option<String>,
+
+    #[clap(
+        long,
+        help = "Write the query records without any mapping to this file (.bq, .vbq, or FASTQ/FASTA)"
+    )]
+    pub unmapped_out: Option<String>,
+
+    #[clap(
+        long,
+        help = "Write the number of primary alignments per target sequence to this TSV file"
+    )]
+    pub counts: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "0",
+        requires = "counts",
+        help = "Minimum MAPQ of alignments counted in --counts"
+    )]
+    pub counts_min_mapq: u32,
+
+    #[clap(
+        long,
+        default_value = "0",
+        requires = "counts",
+        help = "Minimum identity (matching bases / alignment block length) of alignments counted in --counts"
+    )]
+    pub counts_min_identity: f64,
+
+    #[clap(
+        long,
+        requires = "counts",
+        help = "Split the counts of --counts into forward and reverse strand columns"
+    )]
+    pub counts_by_strand: bool,
+
+    #[clap(
+        long,
+        requires = "counts",
+        conflicts_with_all = ["output_path", "output_dir", "sam", "sort", "report_unmapped"],
+        help = "Only write the --counts table (no alignment output)"
+    )]
+    pub counts_only: bool,
+
+    #[clap(
+        long,
+        help = "Write the alignments in the order of the query records (regardless of -T)"
+    )]
+    pub ordered: bool,
+
+    #[clap(
+        long,
+        help = "Coordinate sort the output and write an index next to it (BAM output only)"
+    )]
+    pub sort: bool,
+
+    #[clap(
+        long,
+        default_value = "768",
+        help = "Maximum memory (in MiB) used to buffer records before spilling sorted runs to disk"
+    )]
+    pub sort_memory: usize,
+
+    #[clap(
+        long,
+        help = "Directory for temporary sorted runs and combined references [default: system temporary directory]"
+    )]
+    pub tmp_dir: Option<String>,
+}
+impl IoOptions {
+    pub fn index_path(&self) -> &str {
+        self.index_path
+            .as_deref()
+            .expect("index path is required without a subcommand")
+    }
+    pub fn query_path(&self) -> Option<&str> {
+        self.query_path.as_deref()
+    }
+    /// Whether only a slice of the BINSEQ records is mapped
+    pub fn is_sliced(&self) -> bool {
+        self.range.is_some() || self.shard.is_some()
+    }
+
+    /// Whether the query records are subsampled
+    pub fn is_sampled(&self) -> bool {
+        self.subsample.is_some() || self.max_reads.is_some()
+    }
+
+    /// Whether reads are filtered before mapping
+    pub fn is_filtered(&self) -> bool {
+        self.min_len.is_some()
+            || self.max_len.is_some()
+            || self.max_n_frac.is_some()
+            || self.min_mean_q.is_some()
+    }
+
+    /// Indices of the BINSEQ records to map (all records without --range or --shard)
+    pub fn record_range(&self, n_records: usize) -> Range<usize> {
+        if let Some(range) = &self.range {
+            range.start.min(n_records)..range.end.min(n_records)
+        } else if let Some((shard, n_shards)) = self.shard {
+            n_records * shard / n_shards..n_records * (shard + 1) / n_shards
+        } else {
+            0..n_records
+        }
+    }
+    pub fn output_format(&self) -> OutputFormat {
+        if self.sam {
+            OutputFormat::Sam
+        } else {
+            self.output_format
+        }
+    }
+}
+
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let Some((start, end)) = s.split_once("..") else {
+        return Err("Expected format: START..END".to_string());
+    };
+    let start = start.parse::<usize>().map_err(|e| e.to_string())?;
+    let end = end.parse::<usize>().map_err(|e| e.to_string())?;
+    if start > end {
+        return Err(format!("Range start ({start}) is after its end ({end})"));
+    }
+    Ok(start..end)
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(format!("Fraction ({fraction}) must be in (0, 1]"));
+    }
+    Ok(fraction)
+}
+
+fn parse_n_frac(s: &str) -> Result<f64, String> {
+    let fraction = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("Fraction ({fraction}) must be in [0, 1]"));
+    }
+    Ok(fraction)
+}
+
+fn parse_shard(s: &str) -> Result<(usize, usize), String> {
+    let Some((shard, n_shards)) = s.split_once('/') else {
+        return Err("Expected format: I/N".to_string());
+    };
+    let shard = shard.parse::<usize>().map_err(|e| e.to_string())?;
+    let n_shards = n_shards.parse::<usize>().map_err(|e| e.to_string())?;
+    if shard >= n_shards {
+        return Err(format!(
+            "Shard index ({shard}) must be below the number of shards ({n_shards})"
+        ));
+    }
+    Ok((shard, n_shards))
+}
+
+/// Format of the query records
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum InputFormat {
+    /// Fixed-length BINSEQ
+    Bq,
+
+    /// Variable-length BINSEQ
+    Vbq,
+
+    /// FASTQ (optionally compressed)
+    Fastq,
+
+    /// FASTA (optionally compressed)
+    Fasta,
+}
+impl InputFormat {
+    pub fn is_binseq(&self) -> bool {
+        matches!(self, Self::Bq | Self::Vbq)
+    }
+}
+
+/// Output format of the alignment records
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Default)]
+pub enum OutputFormat {
+    /// Pairwise mApping Format (PAF)
+    #[default]
+    Paf,
+
+    /// Sequence Alignment/Map format (SAM)
+    Sam,
+
+    /// BGZF-compressed binary SAM (BAM)
+    Bam,
+}
+impl OutputFormat {
+    /// Whether the output format requires base-level alignment (CIGAR)
+    pub fn requires_cigar(&self) -> bool {
+        matches!(self, Self::Sam | Self::Bam)
+    }
+
+    /// File extension of the output format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Paf => "paf",
+            Self::Sam => "sam",
+            Self::Bam => "bam",
+        }
+    }
+}
+
+/// Compression of the alignment output
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Default)]
+pub enum OutputCompression {
+    /// Uncompressed
+    #[default]
+    None,
+
+    /// gzip (compressed by the writer thread)
+    Gzip,
+
+    /// Blocked gzip (compressed in parallel, readable by gzip)
+    Bgzip,
+
+    /// Zstandard (compressed in parallel, one frame per batch)
+    Zstd,
+
+    /// bzip2 (compressed by the writer thread)
+    Bzip2,
+
+    /// xz (compressed by the writer thread)
+    Xz,
+}
+impl OutputCompression {
+    /// Compression implied by the extension of an output path
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".bgz") {
+            Self::Bgzip
+        } else if path.ends_with(".gz") {
+            Self::Gzip
+        } else if path.ends_with(".zst") {
+            Self::Zstd
+        } else if path.ends_with(".bz2") {
+            Self::Bzip2
+        } else if path.ends_with(".xz") {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+
+    /// File extension appended to outputs named after their input
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip | Self::Bgzip => ".gz",
+            Self::Zstd => ".zst",
+            Self::Bzip2 => ".bz2",
+            Self::Xz => ".xz",
+        }
+    }
 }
@@ -7,6 +7,19 @@ pub struct MappingOptions {
     #[clap(short = 'c', long, help = "Output CIGAR string")]
     pub cigar: bool,
 
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "short",
+        help = "Output the cs tag (implies -c)"
+    )]
+    pub cs: Option<CsFormat>,
+
+    #[clap(long = "MD", help = "Output the MD tag (implies -c)")]
+    pub md: bool,
+
     #[clap(
         short = 'f',
         long,
@@ -104,6 +117,16 @@ fn parse_integer_tuple(s: &str) -> Result<(i32, i32), String> {
     ))
 }
 
+/// Form of the cs difference string
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CsFormat {
+    /// Identical bases are written as their run length (`:10`)
+    Short,
+
+    /// Identical bases are written as the sequence itself (`=ACGTACGTAC`)
+    Long,
+}
+
 /// How to find canonical splicing sites GT-AG
 #[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Default)]
 pub enum SpliceSiteMode {
@@ -5,9 +5,9 @@ mod mapopts;
 mod preset;
 mod runopts;
 
-pub use command::Cli;
+pub use command::{Cli, Command};
 pub use idxopts::IndexOptions;
-pub use ioopts::IoOptions;
-pub use mapopts::MappingOptions;
+pub use ioopts::{InputFormat, IoOptions, OutputCompression, OutputFormat};
+pub use mapopts::{CsFormat, MappingOptions};
 pub use preset::PresetWrapper;
 pub use runopts::RunOptions;
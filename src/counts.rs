@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Write},
+};
+
+use anyhow::Result;
+use minimap2::{Mapping, Strand};
+use parking_lot::Mutex;
+
+use crate::io::transparent_writer;
+
+/// Counts of alignments per target id, split by strand (forward, reverse)
+pub type LocalCounts = HashMap<i32, [u64; 2]>;
+
+/// Number of primary alignments per target, written as a TSV table once mapping is complete
+///
+/// Workers count into a local map which is merged into the table with every finished batch.
+pub struct CountsTable {
+    path: String,
+
+    /// Minimum MAPQ of a counted alignment
+    min_mapq: u32,
+
+    /// Minimum identity (matching bases over the alignment block length) of a counted alignment
+    min_identity: f64,
+
+    /// Whether forward and reverse strand counts are written as separate columns
+    by_strand: bool,
+
+    counts: Mutex<LocalCounts>,
+}
+impl CountsTable {
+    pub fn new(path: &str, min_mapq: u32, min_identity: f64, by_strand: bool) -> Result<Self> {
+        // Fail early on paths which cannot be written
+        transparent_writer(Some(path))?;
+        Ok(Self {
+            path: path.to_string(),
+            min_mapq,
+            min_identity,
+            by_strand,
+            counts: Mutex::new(LocalCounts::new()),
+        })
+    }
+
+    /// Counts the primary alignment of a read (if it passes the thresholds)
+    pub fn count(&self, local: &mut LocalCounts, mappings: &[Mapping]) {
+        let Some(mapping) = mappings.iter().find(|mapping| mapping.is_primary) else {
+            return;
+        };
+        if mapping.mapq < self.min_mapq || identity(mapping) < self.min_identity {
+            return;
+        }
+        let strand = match mapping.strand {
+            Strand::Forward => 0,
+            Strand::Reverse => 1,
+        };
+        local.entry(mapping.target_id).or_default()[strand] += 1;
+    }
+
+    /// Adds the counts of a worker, leaving its map empty
+    pub fn absorb(&self, local: &mut LocalCounts) {
+        if local.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.lock();
+        for (target_id, [forward, reverse]) in local.drain() {
+            let total = counts.entry(target_id).or_default();
+            total[0] += forward;
+            total[1] += reverse;
+        }
+    }
+
+    /// Writes one row per reference sequence (including targets without reads)
+    pub fn write(&self, references: &[(String, u32)]) -> Result<()> {
+        let counts = self.counts.lock();
+        let mut writer = BufWriter::new(transparent_writer(Some(&self.path))?);
+        if self.by_strand {
+            writeln!(writer, "target\tlength\treads\tforward\treverse")?;
+        } else {
+            writeln!(writer, "target\tlength\treads")?;
+        }
+        for (target_id, (name, len)) in references.iter().enumerate() {
+            let [forward, reverse] = counts.get(&(target_id as i32)).copied().unwrap_or_default();
+            if self.by_strand {
+                writeln!(
+                    writer,
+                    "{name}\t{len}\t{}\t{forward}\t{reverse}",
+                    forward + reverse
+                )?;
+            } else {
+                writeln!(writer, "{name}\t{len}\t{}", forward + reverse)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Fraction of matching bases in the alignment block (as PAF columns 10 and 11)
+fn identity(mapping: &Mapping) -> f64 {
+    if mapping.block_len > 0 {
+        mapping.match_len as f64 / mapping.block_len as f64
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,126 @@
+use std::mem;
+
+use serde::Serialize;
+
+/// Offset of Phred quality scores in FASTQ and BINSEQ qualities
+const PHRED_OFFSET: u8 = 33;
+
+/// Filter a query record was rejected by
+#[derive(Clone, Copy, Debug)]
+pub enum Rejection {
+    MinLen,
+    MaxLen,
+    MaxNFrac,
+    MinMeanQ,
+}
+
+/// Pre-mapping filters on the length, N content, and mean quality of reads
+///
+/// Pairs are rejected if either mate fails a filter. The mean quality is derived from the mean
+/// error probability of the bases (as in most ONT tools) and only checked for reads with
+/// qualities.
+pub struct ReadFilter {
+    min_len: Option<usize>,
+    max_len: Option<usize>,
+    max_n_frac: Option<f64>,
+
+    /// Maximum mean error probability (from the minimum mean quality)
+    max_error: Option<f64>,
+
+    /// Error probability of each quality score
+    error_probs: [f64; 94],
+}
+impl ReadFilter {
+    pub fn new(
+        min_len: Option<usize>,
+        max_len: Option<usize>,
+        max_n_frac: Option<f64>,
+        min_mean_q: Option<f64>,
+    ) -> Self {
+        Self {
+            min_len,
+            max_len,
+            max_n_frac,
+            max_error: min_mean_q.map(error_probability),
+            error_probs: std::array::from_fn(|q| error_probability(q as f64)),
+        }
+    }
+
+    /// Checks a read and its mate (sequence and qualities) against the filters
+    pub fn check(
+        &self,
+        read: (&[u8], Option<&[u8]>),
+        mate: Option<(&[u8], Option<&[u8]>)>,
+    ) -> Option<Rejection> {
+        self.check_read(read.0, read.1)
+            .or_else(|| mate.and_then(|(seq, qual)| self.check_read(seq, qual)))
+    }
+
+    fn check_read(&self, seq: &[u8], qual: Option<&[u8]>) -> Option<Rejection> {
+        if self.min_len.is_some_and(|min_len| seq.len() < min_len) {
+            return Some(Rejection::MinLen);
+        }
+        if self.max_len.is_some_and(|max_len| seq.len() > max_len) {
+            return Some(Rejection::MaxLen);
+        }
+        if let Some(max_n_frac) = self.max_n_frac {
+            let n_count = seq.iter().filter(|&&c| c == b'N' || c == b'n').count();
+            if n_count as f64 > max_n_frac * seq.len() as f64 {
+                return Some(Rejection::MaxNFrac);
+            }
+        }
+        if let (Some(max_error), Some(qual)) = (self.max_error, qual.filter(|q| !q.is_empty())) {
+            if self.mean_error(qual) > max_error {
+                return Some(Rejection::MinMeanQ);
+            }
+        }
+        None
+    }
+
+    fn mean_error(&self, qual: &[u8]) -> f64 {
+        let total = qual
+            .iter()
+            .map(|&q| {
+                let q = q.saturating_sub(PHRED_OFFSET) as usize;
+                self.error_probs[q.min(self.error_probs.len() - 1)]
+            })
+            .sum::<f64>();
+        total / qual.len() as f64
+    }
+}
+
+fn error_probability(q: f64) -> f64 {
+    10f64.powf(-q / 10.0)
+}
+
+/// Number of query records which passed or were rejected by each filter (pairs count once)
+#[derive(Clone, Default, Serialize)]
+pub struct FilterStats {
+    pub passed_records: u64,
+    pub rejected_min_len: u64,
+    pub rejected_max_len: u64,
+    pub rejected_max_n_frac: u64,
+    pub rejected_min_mean_q: u64,
+}
+impl FilterStats {
+    pub fn add(&mut self, rejection: Option<Rejection>) {
+        let count = match rejection {
+            None => &mut self.passed_records,
+            Some(Rejection::MinLen) => &mut self.rejected_min_len,
+            Some(Rejection::MaxLen) => &mut self.rejected_max_len,
+            Some(Rejection::MaxNFrac) => &mut self.rejected_max_n_frac,
+            Some(Rejection::MinMeanQ) => &mut self.rejected_min_mean_q,
+        };
+        *count += 1;
+    }
+
+    /// Adds the counts of a worker, resetting them
+    pub fn absorb(&mut self, other: &mut Self) {
+        let other = mem::take(other);
+        self.passed_records += other.passed_records;
+        self.rejected_min_len += other.rejected_min_len;
+        self.rejected_max_len += other.rejected_max_len;
+        self.rejected_max_n_frac += other.rejected_max_n_frac;
+        self.rejected_min_mean_q += other.rejected_min_mean_q;
+    }
+}
@@ -1,10 +1,123 @@
-use std::io::Write;
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
 
 use anyhow::{bail, Result};
-use minimap2::{Aligner, Built, IdxOpt, MapOpt};
+use minimap2::{Aligner, Built, IdxOpt, MapOpt, PresetSet};
 
 use crate::cli::{IndexOptions, MappingOptions};
 
+/// Magic bytes of a minimap2 index file
+const MMI_MAGIC: &[u8; 4] = b"MMI\x02";
+
+/// Index flag: homopolymer-compressed minimizers
+const MM_I_HPC: i16 = 1;
+
+/// Header of a prebuilt minimap2 index
+struct MmiHeader {
+    w: i16,
+    k: i16,
+    flag: i16,
+}
+
+/// Reads the header of a prebuilt minimap2 index (returns `None` if the file is not an index)
+fn read_mmi_header(path: &str) -> Result<Option<MmiHeader>> {
+    let mut buf = [0u8; 24];
+    let mut file = File::open(path)?;
+    if file.read_exact(&mut buf).is_err() || &buf[..4] != MMI_MAGIC {
+        return Ok(None);
+    }
+    let field = |i: usize| u32::from_le_bytes(buf[4 + 4 * i..8 + 4 * i].try_into().unwrap());
+    Ok(Some(MmiHeader {
+        w: field(0) as i16,
+        k: field(1) as i16,
+        flag: field(4) as i16,
+    }))
+}
+
+/// Index options of the preset with the user-provided overrides applied
+///
+/// Options which are not set fall back to the values of the preset.
+fn requested_index_options(idx_options: IndexOptions) -> IdxOpt {
+    let mut idxopt = Aligner::builder()
+        .preset(idx_options.preset().into())
+        .idxopt;
+    if let Some(k) = idx_options.kmer_size {
+        idxopt.k = k;
+    }
+    if let Some(w) = idx_options.window_size {
+        idxopt.w = w;
+    }
+    idxopt
+}
+
+/// Validates that a prebuilt index matches the requested index options
+fn validate_mmi(path: &str, header: &MmiHeader, idx_options: IndexOptions) -> Result<()> {
+    let requested = requested_index_options(idx_options);
+    if header.k != requested.k || header.w != requested.w {
+        bail!(
+            "Prebuilt index {} was built with k={}, w={} but k={}, w={} was requested",
+            path,
+            header.k,
+            header.w,
+            requested.k,
+            requested.w
+        );
+    }
+    if header.flag & MM_I_HPC != requested.flag & MM_I_HPC {
+        bail!(
+            "Prebuilt index {} was built {} homopolymer compression but the preset {} it",
+            path,
+            if header.flag & MM_I_HPC != 0 {
+                "with"
+            } else {
+                "without"
+            },
+            if requested.flag & MM_I_HPC != 0 {
+                "requires"
+            } else {
+                "does not use"
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Creates an aligner builder with the preset and index options applied
+fn index_builder(idx_options: IndexOptions, n_threads: usize) -> Aligner<PresetSet> {
+    let mut builder = Aligner::builder()
+        .preset(idx_options.preset().into())
+        .with_index_threads(n_threads);
+    builder.idxopt = requested_index_options(idx_options);
+    builder
+}
+
+/// Builds a minimap2 index of the reference and writes it to `output_path`
+pub fn write_index(
+    reference_path: &str,
+    output_path: &str,
+    idx_options: IndexOptions,
+    n_threads: usize,
+    log_options: bool,
+) -> Result<()> {
+    if read_mmi_header(reference_path)?.is_some() {
+        bail!("Input file is already a minimap2 index: {}", reference_path);
+    }
+    eprintln!("Building index...");
+    let start = std::time::Instant::now();
+    match index_builder(idx_options, n_threads).with_index(reference_path, Some(output_path)) {
+        Ok(aligner) => {
+            eprintln!("Index written to {} in {:?}", output_path, start.elapsed());
+            if log_options {
+                pprint_index(&mut std::io::stderr(), aligner.idxopt)?;
+            }
+            Ok(())
+        }
+        Err(err) => bail!("Error building index: {}", err),
+    }
+}
+
 pub fn build_index(
     path: &str,
     map_options: MappingOptions,
@@ -12,19 +125,20 @@ pub fn build_index(
     n_threads: usize,
     log_options: bool,
 ) -> Result<Aligner<Built>> {
-    eprintln!("Building index...");
+    if let Some(header) = read_mmi_header(path)? {
+        validate_mmi(path, &header, idx_options)?;
+        eprintln!("Loading prebuilt index...");
+    } else {
+        eprintln!("Building index...");
+    }
     let start = std::time::Instant::now();
-    let aligner = Aligner::builder()
-        .preset(idx_options.preset.into())
-        .with_index_threads(n_threads)
-        .with_index(path, None);
+    let aligner = index_builder(idx_options, n_threads).with_index(path, None);
     let duration = start.elapsed();
     eprintln!("Index built in {:?}", duration);
 
     match aligner {
         Ok(mut aligner) => {
             update_map_options(&mut aligner, map_options);
-            update_index_options(&mut aligner, idx_options);
 
             if log_options {
                 pprint_index(&mut std::io::stderr(), aligner.idxopt)?;
@@ -92,15 +206,6 @@ fn update_map_options(aligner: &mut Aligner<Built>, map_options: MappingOptions)
     }
 }
 
-fn update_index_options(aligner: &mut Aligner<Built>, idx_options: IndexOptions) {
-    if let Some(k) = idx_options.kmer_size {
-        aligner.idxopt.k = k;
-    }
-    if let Some(w) = idx_options.window_size {
-        aligner.idxopt.w = w;
-    }
-}
-
 fn pprint_index<W: Write>(writer: &mut W, opt: IdxOpt) -> Result<()> {
     writeln!(writer, "== Index Options ==")?;
     writeln!(writer, "  k: {}", opt.k)?;
@@ -1,13 +1,186 @@
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+
+use crate::cli::InputFormat;
+
+/// Path used to read the query from stdin
+pub const STDIN_PATH: &str = "-";
+
+/// Decompressed stdin stream which was already peeked to detect its format
+static STDIN_STREAM: Mutex<Option<Box<dyn Read + Send>>> = Mutex::new(None);
 
 pub fn transparent_reader(input: &str) -> Result<Box<dyn Read + Send>> {
+    if input == STDIN_PATH {
+        if let Some(stream) = STDIN_STREAM.lock().take() {
+            return Ok(stream);
+        }
+        let (stream, _comp) = niffler::send::get_reader(Box::new(std::io::stdin()))?;
+        return Ok(stream);
+    }
     let (stream, _comp) = niffler::send::from_path(input)?;
     Ok(stream)
 }
 
+/// Magic number of fixed-length BINSEQ files ("BSEQ")
+const BQ_MAGIC: u32 = 0x51455342;
+
+/// Magic number of variable-length BINSEQ files ("VSEQ")
+const VBQ_MAGIC: u32 = 0x51455356;
+
+/// Detects the format of a query file from its leading bytes
+///
+/// BINSEQ files are identified by their magic number and text formats by the first
+/// record marker after decompression.
+pub fn detect_format(path: &str) -> Result<InputFormat> {
+    if path == STDIN_PATH {
+        return detect_stdin_format();
+    }
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    if file.read_exact(&mut magic).is_ok() {
+        if let Some(format) = binseq_format(&magic) {
+            return Ok(format);
+        }
+    }
+    detect_text_format(&mut BufReader::new(transparent_reader(path)?), path)
+}
+
+/// Detects the format of stdin without consuming any of its records
+///
+/// The peeked stream is kept so that it is returned by the next `transparent_reader` of stdin.
+/// BINSEQ input is rejected as its readers require random access to a file.
+fn detect_stdin_format() -> Result<InputFormat> {
+    let mut stream = BufReader::new(transparent_reader(STDIN_PATH)?);
+    if binseq_format(stream.fill_buf()?).is_some() {
+        bail!("{}", BINSEQ_STDIN_ERROR);
+    }
+    let format = detect_text_format(&mut stream, "<stdin>")?;
+    *STDIN_STREAM.lock() = Some(Box::new(stream));
+    Ok(format)
+}
+
+/// Explanation of why BINSEQ input cannot be streamed
+pub const BINSEQ_STDIN_ERROR: &str = "BINSEQ (BQ/VBQ) input cannot be read from stdin: \
+    the readers memory-map the file for random access. \
+    Write the stream to a file first, or stream FASTQ/FASTA instead";
+
+fn binseq_format(bytes: &[u8]) -> Option<InputFormat> {
+    let magic = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+    match magic {
+        BQ_MAGIC => Some(InputFormat::Bq),
+        VBQ_MAGIC => Some(InputFormat::Vbq),
+        _ => None,
+    }
+}
+
+/// Detects FASTQ or FASTA from the first record marker of a decompressed stream
+///
+/// Leading whitespace is consumed but the record marker itself is left in the stream.
+fn detect_text_format<R: BufRead>(stream: &mut R, path: &str) -> Result<InputFormat> {
+    loop {
+        let Some(&byte) = stream.fill_buf()?.first() else {
+            bail!(
+                "Unable to detect the format of an empty query file: {}",
+                path
+            );
+        };
+        match byte {
+            b'@' => return Ok(InputFormat::Fastq),
+            b'>' => return Ok(InputFormat::Fasta),
+            c if c.is_ascii_whitespace() => stream.consume(1),
+            _ => bail!(
+                "Unable to detect the format of query file (expected BINSEQ, FASTQ, or FASTA): {}",
+                path
+            ),
+        }
+    }
+}
+
+/// A query input (a single file or a mate pair) and the sample name it is reported under
+pub struct QueryInput {
+    pub sample: String,
+    pub path: String,
+    pub mate_path: Option<String>,
+}
+impl QueryInput {
+    pub fn new(path: String, mate_path: Option<String>) -> Self {
+        Self {
+            sample: sample_name(&path, mate_path.is_some()),
+            path,
+            mate_path,
+        }
+    }
+}
+
+/// Derives a sample name from a query path by removing its directory and extensions
+///
+/// The `_R1` suffix of the first file of a pair is removed as well.
+pub fn sample_name(path: &str, paired: bool) -> String {
+    if path == STDIN_PATH {
+        return "stdin".to_string();
+    }
+    let mut name = std::path::Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().into_owned());
+    for extensions in [
+        &[".gz", ".bz2", ".xz", ".zst"][..],
+        &[
+            ".fq", ".fastq", ".fa", ".fasta", ".fna", ".bq", ".vbq", ".binseq",
+        ][..],
+    ] {
+        if let Some(stem) = extensions.iter().find_map(|ext| name.strip_suffix(ext)) {
+            name = stem.to_string();
+        }
+    }
+    if paired {
+        if let Some(stem) = name.strip_suffix("_R1") {
+            name = stem.to_string();
+        }
+    }
+    name
+}
+
+/// Reads a manifest of query inputs
+///
+/// Each non-empty line holds a query path, or the R1 and R2 paths of a pair separated by a tab.
+/// Lines starting with `#` are ignored. Relative paths are relative to the manifest's directory.
+pub fn read_manifest(path: &str) -> Result<Vec<QueryInput>> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let resolve = |query_path: &str| {
+        if query_path == STDIN_PATH || Path::new(query_path).is_absolute() {
+            query_path.to_string()
+        } else {
+            dir.join(query_path).to_string_lossy().into_owned()
+        }
+    };
+    let mut inputs = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields[..] {
+            [path] => inputs.push(QueryInput::new(resolve(path), None)),
+            [r1, r2] => inputs.push(QueryInput::new(resolve(r1), Some(resolve(r2)))),
+            _ => bail!(
+                "Invalid line {} in manifest {}: expected one path or two tab-separated paths",
+                i + 1,
+                path
+            ),
+        }
+    }
+    if inputs.is_empty() {
+        bail!("Manifest {} does not list any query files", path);
+    }
+    Ok(inputs)
+}
+
 pub fn transparent_writer(output: Option<&str>) -> Result<Box<dyn Write + Send>> {
     if let Some(path) = output {
         let stream = File::create(path)?;
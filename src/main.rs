@@ -1,106 +1,418 @@
 use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Instant;
 
-use anyhow::Result;
-use binseq::{BinseqReader, ParallelReader};
+use anyhow::{bail, Result};
+use binseq::{bq, vbq, BinseqReader};
 use clap::Parser;
 
 mod align;
+mod bam;
+mod bgzf;
+mod blocks;
 mod cli;
+mod counts;
+mod filter;
 mod index;
 mod io;
+mod mapper;
+mod names;
+mod ordered;
+mod pool;
+mod reads;
+mod reference;
+mod sam;
+mod sample;
+mod sink;
 mod stats;
 
 use align::ParallelAlignment;
-use cli::Cli;
-use index::build_index;
-use io::{transparent_reader, transparent_writer};
-use paraseq::{fastq, parallel::ParallelReader as FastqParallelReader};
-use stats::Runtime;
+use bam::BamSorter;
+use cli::{Cli, Command, InputFormat, OutputCompression, OutputFormat};
+use counts::CountsTable;
+use filter::ReadFilter;
+use index::{build_index, write_index};
+use io::{
+    detect_format, read_manifest, transparent_reader, transparent_writer, QueryInput,
+    BINSEQ_STDIN_ERROR, STDIN_PATH,
+};
+use names::{NameTemplate, QueryNames, QueryNaming, DEFAULT_NAMES_TEMPLATE, DEFAULT_TEMPLATE};
+use ordered::{ReorderBuffer, BATCHES_PER_THREAD};
+use paraseq::{fasta, fastq};
+use pool::WorkerPool;
+use reads::ReadWriter;
+use reference::ReferenceFiles;
+use sample::Sampler;
+use stats::{InputStats, Runtime};
 
 fn report_runtime(
     program_start: Instant,
-    map_start: Instant,
-    num_records: usize,
+    aligner: &ParallelAlignment,
+    inputs: Vec<InputStats>,
     path: Option<&str>,
 ) -> Result<()> {
-    let stats = Runtime::new(program_start, map_start, num_records);
+    let mut stats = Runtime::new(
+        program_start,
+        aligner.start_time(),
+        aligner.num_records(),
+        aligner.index_options(),
+        inputs,
+        aligner.sampling_summary(),
+        aligner.mapping_summary(),
+    );
+    stats.filters = aligner.filter_summary();
+    stats.references = aligner.reference_summary();
     let mut wtr = transparent_writer(path)?;
     serde_json::to_writer_pretty(&mut wtr, &stats)?;
     wtr.flush()?;
     Ok(())
 }
 
+/// Processes FASTQ/FASTA records as single reads, interleaved pairs, or paired files
 fn process_fastq(
-    aligner: ParallelAlignment,
+    aligner: &ParallelAlignment,
+    pool: &WorkerPool,
     query_path: &str,
-    n_threads: usize,
-    start_time: Instant,
-    log_path: Option<&str>,
+    mate_path: Option<&str>,
+    interleaved: bool,
+    format: InputFormat,
 ) -> Result<()> {
+    let mate_stream = mate_path.map(transparent_reader).transpose()?;
     let stream = transparent_reader(query_path)?;
-    let reader = fastq::Reader::new(stream);
-    reader.process_parallel(aligner.clone(), n_threads)?;
-    aligner.finish_pbar();
-    report_runtime(
-        start_time,
-        aligner.start_time(),
-        aligner.num_records(),
-        log_path,
-    )
+    if format == InputFormat::Fasta {
+        let reader = fasta::Reader::new(stream);
+        let mate_reader = mate_stream.map(fasta::Reader::new);
+        pool.map_records(aligner, reader, mate_reader, interleaved)
+    } else {
+        let reader = fastq::Reader::new(stream);
+        let mate_reader = mate_stream.map(fastq::Reader::new);
+        pool.map_records(aligner, reader, mate_reader, interleaved)
+    }
 }
 
-fn process_binseq(
-    aligner: ParallelAlignment,
-    query_path: &str,
-    n_threads: usize,
-    start_time: Instant,
-    log_path: Option<&str>,
-) -> Result<()> {
-    let reader = BinseqReader::new(query_path)?;
-    reader.process_parallel(aligner.clone(), n_threads)?;
-    aligner.finish_pbar();
-    report_runtime(
-        start_time,
-        aligner.start_time(),
-        aligner.num_records(),
-        log_path,
-    )
+/// Opens a BINSEQ file
+///
+/// Readers are constructed directly as the file extension may not match the format.
+fn open_binseq(query_path: &str, format: InputFormat) -> Result<BinseqReader> {
+    Ok(if format == InputFormat::Vbq {
+        BinseqReader::Vbq(vbq::MmapReader::new(query_path)?)
+    } else {
+        BinseqReader::Bq(bq::MmapReader::new(query_path)?)
+    })
+}
+
+/// Names the BINSEQ records of a query input with the template and its read names
+///
+/// Read names are taken from `--names` or a `<query>.names` sidecar next to the query file.
+fn query_naming(
+    input: &QueryInput,
+    format: InputFormat,
+    names_path: Option<&str>,
+    template: Option<&NameTemplate>,
+) -> Result<QueryNaming> {
+    let names_path = match names_path {
+        Some(_) if !format.is_binseq() => {
+            bail!("Read names (--names) are only supported for BINSEQ input")
+        }
+        Some(path) => Some(path.to_string()),
+        None if format.is_binseq() => QueryNames::discover(&input.path),
+        None => None,
+    };
+    let names = names_path
+        .as_deref()
+        .map(QueryNames::from_path)
+        .transpose()?;
+    let naming = match template {
+        Some(template) => QueryNaming::new(template, &input.sample, names)?,
+        None => {
+            let default = if names.is_some() {
+                DEFAULT_NAMES_TEMPLATE
+            } else {
+                DEFAULT_TEMPLATE
+            };
+            QueryNaming::new(&NameTemplate::parse(default)?, &input.sample, names)?
+        }
+    };
+    if format.is_binseq() {
+        naming.validate(
+            open_binseq(&input.path, format)?.num_records()?,
+            &input.path,
+        )?;
+    }
+    Ok(naming)
+}
+
+/// Detects (or validates the overridden) format of a query input
+fn input_format(
+    input: &QueryInput,
+    format: Option<InputFormat>,
+    interleaved: bool,
+) -> Result<InputFormat> {
+    if input.path == STDIN_PATH && input.mate_path.as_deref() == Some(STDIN_PATH) {
+        bail!("Only one of the paired query files can be read from stdin");
+    }
+    let input_format = match format {
+        Some(format) => format,
+        None => detect_format(&input.path)?,
+    };
+    if let (None, Some(mate_path)) = (format, &input.mate_path) {
+        if detect_format(mate_path)? != input_format {
+            bail!(
+                "Query files {} and {} have different formats",
+                input.path,
+                mate_path
+            );
+        }
+    }
+    if input_format.is_binseq() && input.path == STDIN_PATH {
+        bail!("{}", BINSEQ_STDIN_ERROR);
+    }
+    if input_format.is_binseq() && (input.mate_path.is_some() || interleaved) {
+        bail!("Paired BINSEQ files are detected automatically - mate files and --interleaved are only supported for FASTQ input");
+    }
+    Ok(input_format)
 }
 
 fn main() -> Result<()> {
-    let args = Cli::parse();
+    let mut args = Cli::parse();
+    if let Some(Command::Index(command)) = args.command {
+        return write_index(
+            &command.reference_path,
+            &command.output_path(),
+            command.index_options,
+            command.run_options.n_threads(),
+            command.run_options.show_options,
+        );
+    }
+
+    let output_format = args.io_options.output_format();
+    if output_format.requires_cigar()
+        || args.mapping_options.cs.is_some()
+        || args.mapping_options.md
+    {
+        args.mapping_options.cigar = true;
+    }
+    let inputs = match &args.io_options.manifest {
+        Some(manifest) => read_manifest(manifest)?,
+        None => vec![QueryInput::new(
+            args.io_options
+                .query_path()
+                .expect("query path is required without a manifest")
+                .to_string(),
+            args.io_options.mate_path.clone(),
+        )],
+    };
+    let formats = inputs
+        .iter()
+        .map(|input| {
+            input_format(
+                input,
+                args.io_options.input_format,
+                args.io_options.interleaved,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if args.io_options.is_sliced() && !formats.iter().all(|format| format.is_binseq()) {
+        bail!("Record ranges (--range and --shard) are only supported for BINSEQ input");
+    }
+    if args.io_options.sort && output_format != OutputFormat::Bam {
+        bail!("Sorting is only supported with BAM output (--output-format bam)");
+    }
+    if args.io_options.counts_only && output_format != OutputFormat::Paf {
+        bail!("No alignments are written with --counts-only (--output-format is not supported)");
+    }
+    let name_template = args
+        .io_options
+        .name_template
+        .as_deref()
+        .map(NameTemplate::parse)
+        .transpose()?;
+    let compression = match (output_format, args.io_options.output_compression) {
+        (OutputFormat::Bam, None | Some(OutputCompression::None)) => OutputCompression::None,
+        (OutputFormat::Bam, Some(_)) => {
+            bail!("BAM output is always BGZF-compressed (--output-compression is not supported)")
+        }
+        (_, Some(compression)) => compression,
+        (_, None) => args
+            .io_options
+            .output_path
+            .as_deref()
+            .map_or(OutputCompression::None, OutputCompression::from_path),
+    };
+
+    // Inputs are either written to separate files or tagged with their read group
+    let split_output = args.io_options.output_dir.is_some();
+    let read_groups = if !split_output && inputs.len() > 1 {
+        inputs.iter().map(|input| input.sample.clone()).collect()
+    } else {
+        Vec::new()
+    };
+    let tag_inputs = !read_groups.is_empty();
+    if split_output || tag_inputs {
+        let mut samples = inputs.iter().map(|input| &input.sample).collect::<Vec<_>>();
+        samples.sort();
+        if let Some(pair) = samples.windows(2).find(|pair| pair[0] == pair[1]) {
+            bail!("Multiple query inputs share the sample name: {}", pair[0]);
+        }
+    }
+    if let Some(dir) = &args.io_options.output_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let output_path = |input: &QueryInput| match &args.io_options.output_dir {
+        Some(dir) => Some(format!(
+            "{}/{}.{}{}",
+            dir,
+            input.sample,
+            output_format.extension(),
+            compression.extension()
+        )),
+        None => args.io_options.output_path.clone(),
+    };
+    let tmp_dir: PathBuf = args
+        .io_options
+        .tmp_dir
+        .clone()
+        .map_or_else(std::env::temp_dir, Into::into);
+    let sorter = || {
+        args.io_options
+            .sort
+            .then(|| BamSorter::new(tmp_dir.clone(), args.io_options.sort_memory << 20))
+    };
 
     let start_time = Instant::now();
+
+    // Several reference files are indexed as one combined FASTA
+    let references = ReferenceFiles::parse(args.io_options.index_path())?;
+    let combined = match references.single_path() {
+        Some(_) => None,
+        None => Some(references.combine(&tmp_dir)?),
+    };
     let index = build_index(
-        &args.io_options.index_path,
+        match &combined {
+            Some((combined, _)) => combined.path(),
+            None => references.single_path().expect("single reference file"),
+        },
         args.mapping_options,
         args.index_options,
         args.run_options.n_threads(),
         args.run_options.show_options,
     )?;
-    let aligner = ParallelAlignment::new(
+    let labels = references.into_labels(&index, combined.map(|(_, labels)| labels))?;
+
+    // The index is built once and shared by all query inputs
+    let mut aligner = ParallelAlignment::new(
         index,
-        args.io_options.output_path,
+        output_path(&inputs[0]),
         args.mapping_options.cigar,
+        output_format,
+        compression,
+        sorter(),
+        read_groups,
     )?;
-
-    let query_path = &args.io_options.query_path;
-    if query_path.ends_with(".bq") || query_path.ends_with(".vbq") {
-        process_binseq(
-            aligner,
-            query_path,
-            args.run_options.n_threads(),
-            start_time,
-            args.run_options.log_path.as_deref(),
+    aligner.set_reference_labels(labels);
+    aligner.set_difference_strings(args.mapping_options.cs, args.mapping_options.md);
+    aligner.set_report_unmapped(args.io_options.report_unmapped);
+    aligner.set_read_outputs(
+        args.io_options
+            .mapped_out
+            .as_deref()
+            .map(ReadWriter::new)
+            .transpose()?,
+        args.io_options
+            .unmapped_out
+            .as_deref()
+            .map(ReadWriter::new)
+            .transpose()?,
+    );
+    aligner.set_counts(
+        args.io_options
+            .counts
+            .as_deref()
+            .map(|path| {
+                CountsTable::new(
+                    path,
+                    args.io_options.counts_min_mapq,
+                    args.io_options.counts_min_identity,
+                    args.io_options.counts_by_strand,
+                )
+            })
+            .transpose()?,
+    );
+    aligner.set_write_alignments(!args.io_options.counts_only);
+    aligner.set_filter(args.io_options.is_filtered().then(|| {
+        ReadFilter::new(
+            args.io_options.min_len,
+            args.io_options.max_len,
+            args.io_options.max_n_frac,
+            args.io_options.min_mean_q,
         )
-    } else {
-        process_fastq(
-            aligner,
-            query_path,
-            args.run_options.n_threads(),
-            start_time,
-            args.run_options.log_path.as_deref(),
+    }));
+    aligner.set_sampler(args.io_options.is_sampled().then(|| {
+        Sampler::new(
+            args.io_options.subsample,
+            args.io_options.max_reads,
+            args.io_options.seed,
         )
+    }));
+    let n_threads = args.run_options.n_threads();
+    let order = args
+        .io_options
+        .ordered
+        .then(|| Arc::new(ReorderBuffer::new(n_threads * BATCHES_PER_THREAD)));
+    aligner.set_order(order.clone());
+    let pool = WorkerPool::new(&aligner, order, n_threads);
+    let mut input_stats = Vec::with_capacity(inputs.len());
+    for (i, (input, format)) in inputs.into_iter().zip(formats).enumerate() {
+        if split_output && i > 0 {
+            aligner.set_output(output_path(&input), sorter())?;
+        }
+        if tag_inputs {
+            aligner.set_read_group(Some(&input.sample));
+        }
+        aligner.set_query_naming(query_naming(
+            &input,
+            format,
+            args.io_options.names.as_deref(),
+            name_template.as_ref(),
+        )?);
+        aligner.reset_sampler();
+        let n_processed = aligner.num_records();
+        if format.is_binseq() {
+            let reader = open_binseq(&input.path, format)?;
+            let range = aligner.sample_range(args.io_options.record_range(reader.num_records()?));
+            pool.map_binseq(&aligner, &input.path, reader, range)?;
+        } else {
+            process_fastq(
+                &aligner,
+                &pool,
+                &input.path,
+                input.mate_path.as_deref(),
+                args.io_options.interleaved,
+                format,
+            )?;
+        }
+        if split_output {
+            aligner.finish_output()?;
+        }
+        input_stats.push(InputStats {
+            n_records: aligner.num_records() - n_processed,
+            sample: input.sample,
+            path: input.path,
+            mate_path: input.mate_path,
+        });
     }
+    pool.finish()?;
+    aligner.finish_pbar();
+    if !split_output {
+        aligner.finish_output()?;
+    }
+    aligner.finish_read_outputs()?;
+    aligner.finish_counts()?;
+    report_runtime(
+        start_time,
+        &aligner,
+        input_stats,
+        args.run_options.log_path.as_deref(),
+    )
 }
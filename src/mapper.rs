@@ -0,0 +1,377 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    num::NonZeroI32,
+    ptr,
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use minimap2::{
+    ffi::{self, mm_idx_t, mm_reg1_t, mm_tbuf_t},
+    Aligner, Alignment, Built, Mapping, Strand,
+};
+
+use crate::cli::CsFormat;
+use crate::sam;
+
+/// Chaining details of a mapping which minimap2 writes as PAF tags
+///
+/// These are dropped by the minimap2-rs mapping functions, so queries are mapped through the
+/// C API directly.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChainScores {
+    /// Number of minimizers on the chain (`cm`)
+    pub n_minimizers: i32,
+
+    /// Chaining score (`s1`)
+    pub chain_score: i32,
+
+    /// Best chaining score of the secondary chains, for primary chains only (`s2`)
+    pub secondary_score: Option<i32>,
+
+    /// Maximal DP alignment score, with base-level alignment only (`ms`)
+    pub max_dp_score: Option<i32>,
+
+    /// Approximate per-base divergence estimated from the chain (`dv`)
+    pub divergence: f32,
+}
+
+/// Mappings of a query with their chaining details
+#[derive(Default)]
+pub struct Mapped {
+    pub mappings: Vec<Mapping>,
+
+    /// Chaining details of each mapping
+    pub scores: Vec<ChainScores>,
+
+    /// Whether minimap2 paired each mapping with a mapping of the mate (a proper fragment)
+    pub proper_frag: Vec<bool>,
+
+    /// Approximate length of repetitive seeds in the query (`rl`)
+    pub repeat_len: i32,
+}
+impl Mapped {
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+}
+
+/// Mapping buffer of minimap2 (one per thread)
+struct ThreadBuffer(*mut mm_tbuf_t);
+impl ThreadBuffer {
+    fn new() -> Self {
+        Self(unsafe { ffi::mm_tbuf_init() })
+    }
+
+    fn repeat_len(&self) -> i32 {
+        unsafe { (*self.0).rep_len }
+    }
+}
+impl Drop for ThreadBuffer {
+    fn drop(&mut self) {
+        unsafe { ffi::mm_tbuf_destroy(self.0) };
+    }
+}
+
+thread_local! {
+    static BUF: RefCell<ThreadBuffer> = RefCell::new(ThreadBuffer::new());
+}
+
+/// Options for converting the regions of a query into mappings
+struct Conversion<'a> {
+    aligner: &'a Aligner<Built>,
+    query_name: Option<Arc<String>>,
+    cs: Option<CsFormat>,
+    md: bool,
+}
+
+/// Maps a single query (as `Aligner::map`, keeping the chaining details)
+pub fn map(
+    aligner: &Aligner<Built>,
+    seq: &[u8],
+    cs: Option<CsFormat>,
+    md: bool,
+    query_name: &[u8],
+) -> Result<Mapped> {
+    if seq.is_empty() {
+        bail!("Sequence is empty");
+    }
+    let name = CString::new(query_name)?;
+    let conversion = Conversion {
+        aligner,
+        query_name: Some(Arc::new(String::from_utf8_lossy(query_name).into_owned())),
+        cs,
+        md,
+    };
+    let mut mapped = Mapped::default();
+    BUF.with_borrow_mut(|buf| {
+        for part in &aligner.idx_parts {
+            let idx: *const mm_idx_t = &**part.as_ref();
+            let mut n_regs = 0;
+            let regs = unsafe {
+                ffi::mm_map(
+                    idx,
+                    seq.len() as c_int,
+                    seq.as_ptr() as *const c_char,
+                    &mut n_regs,
+                    buf.0,
+                    &aligner.mapopt,
+                    name.as_ptr(),
+                )
+            };
+            unsafe { conversion.convert(idx, regs, n_regs, seq, false, 0, &mut mapped) };
+            mapped.repeat_len = mapped.repeat_len.max(buf.repeat_len());
+        }
+    });
+    Ok(mapped)
+}
+
+/// Maps both segments of a pair as a fragment (as `Aligner::map_pair`, keeping the chaining
+/// details)
+pub fn map_pair(
+    aligner: &Aligner<Built>,
+    seq1: &[u8],
+    seq2: &[u8],
+    cs: Option<CsFormat>,
+    md: bool,
+    query_name: &[u8],
+) -> Result<(Mapped, Mapped)> {
+    if seq1.is_empty() || seq2.is_empty() {
+        bail!("Sequence is empty");
+    }
+    let name = CString::new(query_name)?;
+    let conversion = Conversion {
+        aligner,
+        query_name: Some(Arc::new(String::from_utf8_lossy(query_name).into_owned())),
+        cs,
+        md,
+    };
+
+    // Segments are mapped in the orientation of the library (e.g. `--pe-ori`)
+    let pe_ori = aligner.mapopt.pe_ori;
+    let revcomp = [
+        pe_ori >= 0 && (pe_ori >> 1) & 1 != 0,
+        pe_ori >= 0 && pe_ori & 1 != 0,
+    ];
+    let oriented = [seq1, seq2]
+        .into_iter()
+        .zip(revcomp)
+        .map(|(seq, revcomp)| revcomp.then(|| sam::reverse_complement(seq)))
+        .collect::<Vec<_>>();
+    let seqs = [
+        oriented[0].as_deref().unwrap_or(seq1),
+        oriented[1].as_deref().unwrap_or(seq2),
+    ];
+    let qlens = seqs.map(|seq| seq.len() as c_int);
+    let mut seq_ptrs = seqs.map(|seq| seq.as_ptr() as *const c_char);
+
+    let mut mapped = [Mapped::default(), Mapped::default()];
+    BUF.with_borrow_mut(|buf| {
+        for part in &aligner.idx_parts {
+            let idx: *const mm_idx_t = &**part.as_ref();
+            let mut n_regs = [0; 2];
+            let mut regs = [ptr::null_mut(); 2];
+            unsafe {
+                ffi::mm_map_frag(
+                    idx,
+                    2,
+                    qlens.as_ptr(),
+                    seq_ptrs.as_mut_ptr(),
+                    n_regs.as_mut_ptr(),
+                    regs.as_mut_ptr(),
+                    buf.0,
+                    &aligner.mapopt,
+                    name.as_ptr(),
+                )
+            };
+            for segment in 0..2 {
+                unsafe {
+                    conversion.convert(
+                        idx,
+                        regs[segment],
+                        n_regs[segment],
+                        seqs[segment],
+                        revcomp[segment],
+                        segment as u8,
+                        &mut mapped[segment],
+                    )
+                };
+                mapped[segment].repeat_len = mapped[segment].repeat_len.max(buf.repeat_len());
+            }
+        }
+    });
+    let [mapped1, mapped2] = mapped;
+    Ok((mapped1, mapped2))
+}
+
+impl Conversion<'_> {
+    /// Converts the regions returned by minimap2 into mappings and frees them
+    ///
+    /// # Safety
+    ///
+    /// `regs` must hold `n_regs` regions allocated by `mm_map` (or `mm_map_frag`) for `seq`.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn convert(
+        &self,
+        idx: *const mm_idx_t,
+        regs: *mut mm_reg1_t,
+        n_regs: c_int,
+        seq: &[u8],
+        revcomp: bool,
+        segment_id: u8,
+        mapped: &mut Mapped,
+    ) {
+        if regs.is_null() {
+            return;
+        }
+        // minimap2 only picks the primary of the top-level regions with base-level alignment
+        if self.aligner.mapopt.flag & ffi::MM_F_CIGAR as i64 == 0 {
+            ffi::mm_set_sam_pri(n_regs, regs);
+        }
+        for i in 0..n_regs as usize {
+            let reg = regs.add(i);
+            let (mapping, scores) = self.mapping(idx, &*reg, seq, revcomp, segment_id);
+            mapped.mappings.push(mapping);
+            mapped.scores.push(scores);
+            mapped.proper_frag.push((*reg).proper_frag() != 0);
+            ffi::free((*reg).p as *mut c_void);
+        }
+        ffi::free(regs as *mut c_void);
+    }
+
+    unsafe fn mapping(
+        &self,
+        idx: *const mm_idx_t,
+        reg: &mm_reg1_t,
+        seq: &[u8],
+        revcomp: bool,
+        segment_id: u8,
+    ) -> (Mapping, ChainScores) {
+        let target = &*(*idx).seq.add(reg.rid as usize);
+        let is_top_level = reg.parent == reg.id;
+        let extra = reg.p.as_ref();
+        let qlen = seq.len() as i32;
+        let (query_start, query_end, reverse) = if revcomp {
+            (qlen - reg.qe, qlen - reg.qs, reg.rev() == 0)
+        } else {
+            (reg.qs, reg.qe, reg.rev() != 0)
+        };
+        let mapping = Mapping {
+            query_name: self.query_name.clone(),
+            query_len: NonZeroI32::new(qlen),
+            query_start,
+            query_end,
+            strand: if reverse {
+                Strand::Reverse
+            } else {
+                Strand::Forward
+            },
+            target_name: Some(Arc::new(
+                CStr::from_ptr(target.name).to_string_lossy().into_owned(),
+            )),
+            target_len: target.len as i32,
+            target_start: reg.rs,
+            target_end: reg.re,
+            target_id: reg.rid,
+            match_len: reg.mlen,
+            block_len: reg.blen,
+            mapq: reg.mapq(),
+            is_primary: is_top_level && reg.sam_pri() > 0,
+            is_supplementary: is_top_level && reg.sam_pri() == 0,
+            is_spliced: reg.is_spliced() != 0,
+            trans_strand: extra.and_then(|extra| match extra.trans_strand() {
+                1 => Some(Strand::Forward),
+                2 => Some(Strand::Reverse),
+                _ => None,
+            }),
+            alignment: extra.map(|_| self.alignment(idx, reg, seq)),
+            segment_id,
+        };
+        let scores = ChainScores {
+            n_minimizers: reg.cnt,
+            chain_score: reg.score,
+            secondary_score: is_top_level.then_some(reg.subsc),
+            max_dp_score: extra.map(|extra| extra.dp_max0),
+            divergence: reg.div,
+        };
+        (mapping, scores)
+    }
+
+    /// Base-level alignment of a region (which must have its extra fields set)
+    unsafe fn alignment(&self, idx: *const mm_idx_t, reg: &mm_reg1_t, seq: &[u8]) -> Alignment {
+        let extra = &*reg.p;
+        let mut cigar = extra
+            .cigar
+            .as_slice(extra.n_cigar as usize)
+            .iter()
+            .map(|op| (op >> 4, (op & 0xf) as u8))
+            .collect::<Vec<_>>();
+        let cigar_str = (!cigar.is_empty()).then(|| {
+            // Clipping is always soft (as minimap2-rs)
+            let qlen = seq.len() as i32;
+            let (clip_start, clip_end) = if reg.rev() != 0 {
+                (qlen - reg.qe, reg.qs)
+            } else {
+                (reg.qs, qlen - reg.qe)
+            };
+            let mut cigar_str = String::new();
+            if clip_start > 0 {
+                cigar_str.push_str(&format!("{clip_start}S"));
+            }
+            for (len, op) in &cigar {
+                cigar_str.push_str(&format!("{len}{}", b"MIDNSHP=X"[*op as usize] as char));
+            }
+            if clip_end > 0 {
+                cigar_str.push_str(&format!("{clip_end}S"));
+            }
+            if self.aligner.cigar_clipping {
+                if clip_start > 0 {
+                    cigar.insert(0, (clip_start as u32, 4));
+                }
+                if clip_end > 0 {
+                    cigar.push((clip_end as u32, 4));
+                }
+            }
+            cigar_str
+        });
+        let cigar = (!cigar.is_empty()).then_some(cigar);
+
+        // The strings are allocated without a memory pool so they can be freed directly
+        let mut buf: *mut c_char = ptr::null_mut();
+        let mut max_len: c_int = 0;
+        let cs = self.cs.map(|format| {
+            ffi::mm_gen_cs(
+                ptr::null_mut(),
+                &mut buf,
+                &mut max_len,
+                idx,
+                reg,
+                seq.as_ptr() as *const c_char,
+                (format == CsFormat::Short) as c_int,
+            );
+            CStr::from_ptr(buf).to_string_lossy().into_owned()
+        });
+        let md = self.md.then(|| {
+            ffi::mm_gen_MD(
+                ptr::null_mut(),
+                &mut buf,
+                &mut max_len,
+                idx,
+                reg,
+                seq.as_ptr() as *const c_char,
+            );
+            CStr::from_ptr(buf).to_string_lossy().into_owned()
+        });
+        ffi::free(buf as *mut c_void);
+
+        Alignment {
+            nm: reg.blen - reg.mlen + extra.n_ambi() as i32,
+            cigar,
+            cigar_str,
+            md,
+            cs,
+            alignment_score: Some(extra.dp_score),
+        }
+    }
+}
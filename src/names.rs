@@ -0,0 +1,196 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::io::transparent_reader;
+
+/// Default name of BINSEQ records without a name sidecar
+pub const DEFAULT_TEMPLATE: &str = "bq.{index}";
+
+/// Default name of BINSEQ records with a name sidecar
+pub const DEFAULT_NAMES_TEMPLATE: &str = "{name}";
+
+/// Extension of the name sidecar discovered next to a BINSEQ query file
+pub const NAMES_EXTENSION: &str = ".names";
+
+/// Original read names of a BINSEQ file, one per record index
+///
+/// The sidecar holds one name per line (optionally compressed). A leading `@` or `>` and
+/// anything after the first whitespace (e.g. FASTQ comments) are dropped.
+pub struct QueryNames {
+    /// Concatenated names
+    data: Vec<u8>,
+
+    /// End of each name in `data`
+    ends: Vec<usize>,
+}
+impl QueryNames {
+    pub fn from_path(path: &str) -> Result<Self> {
+        let mut data = Vec::new();
+        let mut ends = Vec::new();
+        let mut reader = BufReader::new(transparent_reader(path)?);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            let name = line
+                .strip_prefix(b"@")
+                .or_else(|| line.strip_prefix(b">"))
+                .unwrap_or(&line);
+            let name = name
+                .split(|c| c.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default();
+            if name.is_empty() {
+                bail!("Empty read name on line {} of {}", ends.len() + 1, path);
+            }
+            data.extend_from_slice(name);
+            ends.push(data.len());
+            line.clear();
+        }
+        Ok(Self { data, ends })
+    }
+
+    /// Sidecar next to a query file (`<query>.names`), if it exists
+    pub fn discover(query_path: &str) -> Option<String> {
+        let path = format!("{query_path}{NAMES_EXTENSION}");
+        Path::new(&path).is_file().then_some(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(index)?;
+        let start = index.checked_sub(1).map_or(0, |prev| self.ends[prev]);
+        Some(&self.data[start..end])
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Sample,
+    Index,
+    Name,
+}
+
+/// Template of BINSEQ record names (e.g. `{sample}.{index}`)
+///
+/// Placeholders are `{sample}` (the sample name of the query input), `{index}` (the record
+/// index in the file), and `{name}` (the name in the sidecar).
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    segments: Vec<Segment>,
+}
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                segments.push(Segment::Literal(rest[..open].to_string()));
+            }
+            let Some(close) = rest[open..].find('}') else {
+                bail!("Unclosed placeholder in name template: {}", template);
+            };
+            segments.push(match &rest[open + 1..open + close] {
+                "sample" => Segment::Sample,
+                "index" => Segment::Index,
+                "name" => Segment::Name,
+                other => bail!(
+                    "Unknown placeholder {{{}}} in name template (expected {{sample}}, {{index}}, or {{name}}): {}",
+                    other,
+                    template
+                ),
+            });
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        if !segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Index | Segment::Name))
+        {
+            bail!(
+                "Name template must contain {{index}} or {{name}} to keep record names unique: {}",
+                template
+            );
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn uses_names(&self) -> bool {
+        self.segments.contains(&Segment::Name)
+    }
+}
+
+/// Names the BINSEQ records of one query input
+pub struct QueryNaming {
+    /// Template with the sample name filled in
+    segments: Vec<Segment>,
+
+    names: Option<QueryNames>,
+}
+impl Default for QueryNaming {
+    /// Names records after their index (`bq.{index}`)
+    fn default() -> Self {
+        Self {
+            segments: vec![Segment::Literal("bq.".to_string()), Segment::Index],
+            names: None,
+        }
+    }
+}
+impl QueryNaming {
+    pub fn new(template: &NameTemplate, sample: &str, names: Option<QueryNames>) -> Result<Self> {
+        if template.uses_names() && names.is_none() {
+            bail!("The name template uses {{name}} but no name sidecar was found for sample {sample} (use --names)");
+        }
+        let segments = template
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Sample => Segment::Literal(sample.to_string()),
+                segment => segment.clone(),
+            })
+            .collect();
+        Ok(Self { segments, names })
+    }
+
+    /// Checks that the sidecar names every record of the query file
+    pub fn validate(&self, n_records: usize, query_path: &str) -> Result<()> {
+        match &self.names {
+            Some(names) if names.len() != n_records => bail!(
+                "Name sidecar of {} holds {} names but the file has {} records",
+                query_path,
+                names.len(),
+                n_records
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the name of a record into the (cleared) buffer
+    pub fn write(&self, index: u64, buf: &mut Vec<u8>) {
+        buf.clear();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => buf.extend_from_slice(literal.as_bytes()),
+                Segment::Index => write!(buf, "{index}").expect("writing to a Vec cannot fail"),
+                Segment::Name => {
+                    if let Some(name) = self
+                        .names
+                        .as_ref()
+                        .and_then(|names| names.get(index as usize))
+                    {
+                        buf.extend_from_slice(name);
+                    }
+                }
+                Segment::Sample => unreachable!("sample is filled in on construction"),
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+/// Number of batches per worker which may be in flight (mapped or waiting to be written)
+pub const BATCHES_PER_THREAD: usize = 4;
+
+/// Holds the output of finished batches until all preceding batches are written
+///
+/// Batches are identified by the half-open range of input positions they cover (record indices
+/// of BINSEQ inputs, batch indices of FASTQ/FASTA inputs), so the next batch to write is the one
+/// starting where the last written batch ended.
+pub struct ReorderBuffer {
+    state: Mutex<ReorderState>,
+    written: Condvar,
+
+    /// Maximum number of reserved batches which are not yet written
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct ReorderState {
+    /// Start of the next batch to write
+    next: u64,
+
+    /// Output of finished batches by their start (with the end of their range)
+    pending: BTreeMap<u64, (u64, Vec<u8>)>,
+
+    /// Number of batches reserved and written since the last reset
+    n_reserved: usize,
+    n_written: usize,
+
+    /// Set while a worker writes batches (without holding the lock)
+    writing: bool,
+
+    /// Set once a worker failed (batches after it will never be written)
+    aborted: bool,
+}
+
+impl ReorderBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(ReorderState::default()),
+            written: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Prepares the buffer for a new input whose first batch starts at `start`
+    pub fn reset(&self, start: u64) {
+        *self.state.lock() = ReorderState {
+            next: start,
+            ..ReorderState::default()
+        };
+    }
+
+    /// Waits until another batch may be read without exceeding the capacity
+    ///
+    /// Returns false if a worker failed and no more batches should be read.
+    pub fn reserve(&self) -> bool {
+        let mut state = self.state.lock();
+        while !state.aborted && state.n_reserved - state.n_written >= self.capacity {
+            self.written.wait(&mut state);
+        }
+        state.n_reserved += 1;
+        !state.aborted
+    }
+
+    /// Stops waiting for batches after a worker failed
+    pub fn abort(&self) {
+        self.state.lock().aborted = true;
+        self.written.notify_all();
+    }
+
+    /// Submits the output of the batch covering `start..end`
+    ///
+    /// The output is written right away if the batch is next in order (followed by any pending
+    /// batches it unblocks), and held in the buffer otherwise. Batches are written without
+    /// holding the lock, by one worker at a time.
+    pub fn submit<F>(&self, start: u64, end: u64, data: Vec<u8>, mut write: F) -> Result<()>
+    where
+        F: FnMut(Vec<u8>) -> Result<()>,
+    {
+        let mut state = self.state.lock();
+        state.pending.insert(start, (end, data));
+
+        // The worker writing the preceding batches also writes this one once it is next
+        if state.writing {
+            return Ok(());
+        }
+        state.writing = true;
+        loop {
+            let mut ready = Vec::new();
+            loop {
+                let next = state.next;
+                let Some((end, data)) = state.pending.remove(&next) else {
+                    break;
+                };
+                ready.push(data);
+                state.next = end;
+            }
+            if ready.is_empty() {
+                state.writing = false;
+                return Ok(());
+            }
+            let n_ready = ready.len();
+            let written =
+                MutexGuard::unlocked(&mut state, || ready.into_iter().try_for_each(&mut write));
+            if written.is_err() {
+                state.writing = false;
+                return written;
+            }
+            state.n_written += n_ready;
+            self.written.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Submits a batch whose output is its label, collecting the written labels
+    fn submit(order: &ReorderBuffer, written: &mut Vec<String>, range: (u64, u64), label: &str) {
+        order
+            .submit(range.0, range.1, label.as_bytes().to_vec(), |data| {
+                written.push(String::from_utf8(data)?);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn writes_out_of_order_batches_in_order() {
+        let order = ReorderBuffer::new(4);
+        let mut written = Vec::new();
+        submit(&order, &mut written, (2, 3), "c");
+        submit(&order, &mut written, (1, 2), "b");
+        assert!(written.is_empty());
+        submit(&order, &mut written, (0, 1), "a");
+        assert_eq!(written, ["a", "b", "c"]);
+        submit(&order, &mut written, (3, 4), "d");
+        assert_eq!(written, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn reserve_waits_for_gap() {
+        let order = ReorderBuffer::new(2);
+        let mut written = Vec::new();
+        assert!(order.reserve());
+        assert!(order.reserve());
+        let reserved = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(order.reserve());
+                reserved.store(true, Ordering::SeqCst);
+            });
+
+            // The second batch finishes first but cannot be written before the first
+            submit(&order, &mut written, (1, 2), "b");
+            thread::sleep(Duration::from_millis(50));
+            assert!(!reserved.load(Ordering::SeqCst));
+
+            submit(&order, &mut written, (0, 1), "a");
+        });
+        assert!(reserved.load(Ordering::SeqCst));
+        assert_eq!(written, ["a", "b"]);
+    }
+
+    #[test]
+    fn writes_without_holding_lock() {
+        let order = &ReorderBuffer::new(4);
+        let written = &Mutex::new(Vec::new());
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                order
+                    .submit(0, 1, b"a".to_vec(), |data| {
+                        started_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                        written.lock().push(String::from_utf8(data)?);
+                        Ok(())
+                    })
+                    .unwrap();
+            });
+
+            // Neither waits for the slow write, which is then followed by the next batch
+            started_rx.recv().unwrap();
+            assert!(order.reserve());
+            order
+                .submit(1, 2, b"b".to_vec(), |_| {
+                    panic!("written by the other worker")
+                })
+                .unwrap();
+            release_tx.send(()).unwrap();
+            release_tx.send(()).unwrap();
+        });
+        assert_eq!(*written.lock(), ["a", "b"]);
+    }
+
+    #[test]
+    fn empty_batches_advance_order() {
+        // A record range starting at 100 where all records of the first batch were skipped
+        let order = ReorderBuffer::new(4);
+        order.reset(100);
+        let mut written = Vec::new();
+        submit(&order, &mut written, (150, 200), "b");
+        submit(&order, &mut written, (100, 150), "");
+        assert_eq!(written, ["", "b"]);
+        submit(&order, &mut written, (200, 250), "c");
+        assert_eq!(written, ["", "b", "c"]);
+    }
+
+    #[test]
+    fn reset_between_inputs() {
+        let order = ReorderBuffer::new(1);
+        let mut written = Vec::new();
+        assert!(order.reserve());
+        submit(&order, &mut written, (0, 1), "a");
+        assert!(order.reserve());
+        submit(&order, &mut written, (1, 2), "b");
+        submit(&order, &mut written, (3, 4), "stale");
+
+        // The next input numbers its batches from zero again (dropping held batches)
+        order.reset(0);
+        assert!(order.reserve());
+        submit(&order, &mut written, (0, 1), "c");
+        submit(&order, &mut written, (2, 3), "e");
+        submit(&order, &mut written, (1, 2), "d");
+        assert_eq!(written, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn abort_stops_reserving() {
+        let order = ReorderBuffer::new(1);
+        assert!(order.reserve());
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| order.reserve());
+            order.abort();
+            assert!(!waiting.join().unwrap());
+        });
+    }
+}
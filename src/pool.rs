@@ -0,0 +1,550 @@
+use std::{
+    fs::File,
+    io::Read,
+    ops::Range,
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, bail, Result};
+use binseq::{bq, BinseqReader, BinseqRecord};
+use paraseq::{
+    fasta, fastq,
+    parallel::{InterleavedParallelProcessor, PairedParallelProcessor, ParallelProcessor},
+    Record,
+};
+use parking_lot::{Condvar, Mutex};
+
+use crate::align::ParallelAlignment;
+use crate::blocks::VbqBlock;
+use crate::ordered::ReorderBuffer;
+use crate::sam;
+use crate::sample::Sampler;
+
+/// Number of BQ records mapped in one batch (as the parallel BQ reader)
+const BQ_BATCH_RECORDS: usize = 1024;
+
+const PAIRED_MISMATCH: &str = "Paired query files have different numbers of records";
+
+/// Records read by the calling thread and mapped by one worker
+pub enum Batch {
+    /// Record sets (with the mate record set of paired files) and the records kept by
+    /// subsampling
+    Fastq(
+        fastq::RecordSet,
+        Option<fastq::RecordSet>,
+        Option<Vec<bool>>,
+    ),
+    Fasta(
+        fasta::RecordSet,
+        Option<fasta::RecordSet>,
+        Option<Vec<bool>>,
+    ),
+
+    /// Range of records in a memory-mapped BQ file (decoded by the worker)
+    Bq(Arc<bq::MmapReader>, Range<usize>),
+
+    /// Block of a VBQ file (decompressed by the worker) with the range of its records to map
+    Vbq(VbqBlock, Range<usize>),
+}
+impl Batch {
+    fn map(&mut self, interleaved: bool, aligner: &mut ParallelAlignment) -> Result<()> {
+        match self {
+            Self::Fastq(batch, mate_batch, selected) => map_records(
+                batch.iter(),
+                mate_batch.as_ref().map(|batch| batch.iter()),
+                interleaved,
+                selected.as_deref(),
+                aligner,
+            ),
+            Self::Fasta(batch, mate_batch, selected) => map_records(
+                batch.iter(),
+                mate_batch.as_ref().map(|batch| batch.iter()),
+                interleaved,
+                selected.as_deref(),
+                aligner,
+            ),
+            Self::Bq(reader, range) => {
+                for index in range.clone() {
+                    binseq::ParallelProcessor::process_record(aligner, reader.get(index)?)?;
+                }
+                Ok(())
+            }
+            Self::Vbq(block, range) => {
+                block.decode()?;
+                for record in block.records() {
+                    if range.contains(&(record.index() as usize)) {
+                        binseq::ParallelProcessor::process_record(aligner, record)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Record set, mate record set, and selection of a FASTQ/FASTA batch
+type RecordSets<B> = (B, Option<B>, Option<Vec<bool>>);
+
+/// A FASTQ or FASTA reader whose record sets are mapped by the worker pool
+pub trait BatchReader {
+    type Batch;
+
+    fn new_batch(&self) -> Self::Batch;
+
+    /// Fills the batch with the next records, returning false at the end of the input
+    fn fill(&mut self, batch: &mut Self::Batch) -> Result<bool>;
+
+    /// Selects the records (or pairs) of a filled batch kept by subsampling (see
+    /// `sample_records`)
+    fn sample(
+        batch: &Self::Batch,
+        paired: bool,
+        interleaved: bool,
+        sampler: &Sampler,
+        selected: &mut Vec<bool>,
+    ) -> Result<bool>;
+
+    /// Wraps a filled batch (and the mate batch, if any) to be sent to a worker
+    fn wrap(
+        batch: Self::Batch,
+        mate_batch: Option<Self::Batch>,
+        selected: Option<Vec<bool>>,
+    ) -> Batch;
+
+    /// Takes back the record sets (and selection) of a mapped batch for reuse
+    fn unwrap(batch: Batch) -> Option<RecordSets<Self::Batch>>;
+}
+
+macro_rules! impl_batch_reader {
+    ($module:ident, $variant:ident) => {
+        impl<R: Read> BatchReader for $module::Reader<R> {
+            type Batch = $module::RecordSet;
+
+            fn new_batch(&self) -> Self::Batch {
+                self.new_record_set()
+            }
+
+            fn fill(&mut self, batch: &mut Self::Batch) -> Result<bool> {
+                Ok(batch.fill(self)?)
+            }
+
+            fn sample(
+                batch: &Self::Batch,
+                paired: bool,
+                interleaved: bool,
+                sampler: &Sampler,
+                selected: &mut Vec<bool>,
+            ) -> Result<bool> {
+                sample_records(batch.iter(), paired, interleaved, sampler, selected)
+            }
+
+            fn wrap(
+                batch: Self::Batch,
+                mate_batch: Option<Self::Batch>,
+                selected: Option<Vec<bool>>,
+            ) -> Batch {
+                Batch::$variant(batch, mate_batch, selected)
+            }
+
+            fn unwrap(batch: Batch) -> Option<RecordSets<Self::Batch>> {
+                match batch {
+                    Batch::$variant(batch, mate_batch, selected) => {
+                        Some((batch, mate_batch, selected))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+impl_batch_reader!(fastq, Fastq);
+impl_batch_reader!(fasta, Fasta);
+
+/// Selects the records (or pairs) of a batch kept by subsampling, in the order of the input
+///
+/// Returns false once `--max-reads` records of the input are selected, leaving the selection
+/// shorter than the batch.
+fn sample_records<Rf, I>(
+    records: I,
+    paired: bool,
+    interleaved: bool,
+    sampler: &Sampler,
+    selected: &mut Vec<bool>,
+) -> Result<bool>
+where
+    Rf: Record,
+    I: Iterator<Item = Result<Rf, paraseq::Error>>,
+{
+    selected.clear();
+    for record in records.step_by(if interleaved { 2 } else { 1 }) {
+        let record = record?;
+        let name = if paired {
+            sam::template_name(record.id())
+        } else {
+            record.id()
+        };
+        match sampler.select_name(name) {
+            Some(keep) => selected.push(keep),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Maps single reads, interleaved pairs, or pairs split over two record sets
+///
+/// Only the records (or pairs) selected by subsampling are mapped, and none after the end of
+/// the selection.
+fn map_records<Rf, I>(
+    mut records: I,
+    mate_records: Option<I>,
+    interleaved: bool,
+    selected: Option<&[bool]>,
+    aligner: &mut ParallelAlignment,
+) -> Result<()>
+where
+    Rf: Record,
+    I: Iterator<Item = Result<Rf, paraseq::Error>>,
+{
+    let mut selected = selected.map(|selected| selected.iter().copied());
+    let mut next_selected = || selected.as_mut().map_or(Some(true), Iterator::next);
+    if let Some(mut mate_records) = mate_records {
+        loop {
+            match (records.next(), mate_records.next()) {
+                (Some(r1), Some(r2)) => match next_selected() {
+                    Some(true) => aligner.process_record_pair(r1?, r2?)?,
+                    Some(false) => {}
+                    None => return Ok(()),
+                },
+                (None, None) => return Ok(()),
+                _ => bail!(PAIRED_MISMATCH),
+            }
+        }
+    }
+    while let Some(r1) = records.next() {
+        let r2 = if interleaved {
+            let Some(r2) = records.next() else {
+                bail!("Interleaved query file has an odd number of records");
+            };
+            Some(r2)
+        } else {
+            None
+        };
+        match (next_selected(), r2) {
+            (Some(true), Some(r2)) => aligner.process_interleaved_pair(r1?, r2?)?,
+            (Some(true), None) => aligner.process_record(r1?)?,
+            (Some(false), _) => {}
+            (None, _) => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+struct Job {
+    /// Aligner configured for the query input of the batch (output, read group, naming)
+    input: Arc<ParallelAlignment>,
+
+    /// Half-open range of input positions covered by the batch (see `ReorderBuffer`)
+    position: (u64, u64),
+
+    batch: Batch,
+    interleaved: bool,
+}
+
+#[derive(Default)]
+struct Progress {
+    state: Mutex<ProgressState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    /// Number of batches sent and not yet mapped
+    n_pending: usize,
+
+    /// First error of a worker (which stops reading)
+    error: Option<anyhow::Error>,
+}
+impl Progress {
+    fn complete(&self) {
+        self.state.lock().n_pending -= 1;
+        self.done.notify_all();
+    }
+
+    fn fail(&self, err: anyhow::Error) {
+        self.state.lock().error.get_or_insert(err);
+        self.done.notify_all();
+    }
+}
+
+/// Reports a panicking worker so waiting for its batch does not hang
+struct PanicGuard<'a>(&'a Progress);
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.fail(anyhow!("Mapping thread panicked"));
+        }
+    }
+}
+
+/// Mapping threads shared by all query inputs
+///
+/// The calling thread reads batches of records (record ranges of BQ files and raw blocks of VBQ
+/// files) and the workers decode and map them, each with its own clone of the aligner. A worker
+/// switches to the configuration of a new query input with its first batch, so the threads are
+/// spawned once for the whole run.
+pub struct WorkerPool {
+    jobs: SyncSender<Job>,
+
+    /// Mapped batches returned for reuse
+    recycled: Receiver<Batch>,
+
+    progress: Arc<Progress>,
+    order: Option<Arc<ReorderBuffer>>,
+    workers: Vec<JoinHandle<()>>,
+}
+impl WorkerPool {
+    pub fn new(
+        aligner: &ParallelAlignment,
+        order: Option<Arc<ReorderBuffer>>,
+        n_threads: usize,
+    ) -> Self {
+        let (jobs, job_rx) = mpsc::sync_channel(n_threads);
+
+        // The receiver is dropped with the last worker so reading stops if all workers fail
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (recycle_tx, recycled) = mpsc::channel();
+        let progress = Arc::new(Progress::default());
+        let workers = (0..n_threads)
+            .map(|thread_id| {
+                let mut aligner = aligner.clone();
+                ParallelProcessor::set_thread_id(&mut aligner, thread_id);
+                let job_rx = job_rx.clone();
+                let recycle_tx = recycle_tx.clone();
+                let progress = progress.clone();
+                let order = order.clone();
+                thread::spawn(move || {
+                    let _guard = PanicGuard(&progress);
+                    if let Err(err) = work(aligner, &job_rx, &recycle_tx, &progress) {
+                        if let Some(order) = &order {
+                            order.abort();
+                        }
+                        progress.fail(err);
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs,
+            recycled,
+            progress,
+            order,
+            workers,
+        }
+    }
+
+    /// Maps a FASTQ/FASTA input (as single reads, interleaved pairs, or paired files)
+    pub fn map_records<R: BatchReader>(
+        &self,
+        aligner: &ParallelAlignment,
+        mut reader: R,
+        mut mate_reader: Option<R>,
+        interleaved: bool,
+    ) -> Result<()> {
+        let input = self.begin_input(aligner, 0);
+        let paired = interleaved || mate_reader.is_some();
+        for index in 0.. {
+            if !self.reserve() {
+                break;
+            }
+            let (mut batch, mut mate_batch, selected) = self
+                .recycled
+                .try_recv()
+                .ok()
+                .and_then(R::unwrap)
+                .unwrap_or_else(|| {
+                    (
+                        reader.new_batch(),
+                        mate_reader.as_ref().map(BatchReader::new_batch),
+                        None,
+                    )
+                });
+            let filled = reader.fill(&mut batch)?;
+            if let (Some(mate_reader), Some(mate_batch)) = (&mut mate_reader, &mut mate_batch) {
+                if mate_reader.fill(mate_batch)? != filled {
+                    bail!(PAIRED_MISMATCH);
+                }
+            }
+            if !filled {
+                break;
+            }
+
+            // Subsampling (and its cutoff) follows the order of the input
+            let (selected, complete) = match aligner.sampler() {
+                Some(sampler) => {
+                    let mut selected = selected.unwrap_or_default();
+                    let complete = R::sample(&batch, paired, interleaved, sampler, &mut selected)?;
+                    (Some(selected), complete)
+                }
+                None => (None, true),
+            };
+            let job = Job {
+                input: input.clone(),
+                position: (index, index + 1),
+                batch: R::wrap(batch, mate_batch, selected),
+                interleaved,
+            };
+            if !self.send(job) || !complete {
+                break;
+            }
+        }
+        self.wait()
+    }
+
+    /// Maps a range of records of a BINSEQ input
+    pub fn map_binseq(
+        &self,
+        aligner: &ParallelAlignment,
+        path: &str,
+        reader: BinseqReader,
+        range: Range<usize>,
+    ) -> Result<()> {
+        let input = self.begin_input(aligner, range.start as u64);
+        let job = |range: Range<usize>, batch| Job {
+            input: input.clone(),
+            position: (range.start as u64, range.end as u64),
+            batch,
+            interleaved: false,
+        };
+        match reader {
+            BinseqReader::Bq(reader) => {
+                let reader = Arc::new(reader);
+                for start in range.clone().step_by(BQ_BATCH_RECORDS) {
+                    let end = (start + BQ_BATCH_RECORDS).min(range.end);
+                    if !self.reserve()
+                        || !self.send(job(start..end, Batch::Bq(reader.clone(), start..end)))
+                    {
+                        break;
+                    }
+                }
+            }
+            BinseqReader::Vbq(reader) => {
+                // Blocks before the range are skipped through the block index
+                let header = reader.header();
+                let mut file = File::open(path)?;
+                for block_range in reader.load_index()?.ranges() {
+                    let first = block_range.cumulative_records as usize;
+                    if first >= range.end {
+                        break;
+                    }
+                    let records = first.max(range.start)
+                        ..(first + block_range.block_records as usize).min(range.end);
+                    if records.is_empty() {
+                        continue;
+                    }
+                    if !self.reserve() {
+                        break;
+                    }
+                    let mut block = match self.recycled.try_recv() {
+                        Ok(Batch::Vbq(block, _)) => block,
+                        _ => VbqBlock::default(),
+                    };
+                    block.read(&mut file, &header, block_range)?;
+                    if !self.send(job(records.clone(), Batch::Vbq(block, records))) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.wait()
+    }
+
+    /// Stops the workers once all query inputs are mapped
+    pub fn finish(self) -> Result<()> {
+        drop(self.jobs);
+        for worker in self.workers {
+            worker
+                .join()
+                .map_err(|_| anyhow!("Mapping thread panicked"))?;
+        }
+        Ok(())
+    }
+
+    /// Captures the configuration of the next query input, whose first batch starts at `start`
+    fn begin_input(&self, aligner: &ParallelAlignment, start: u64) -> Arc<ParallelAlignment> {
+        // Batches of the previous input may not fit the next one
+        while self.recycled.try_recv().is_ok() {}
+        if let Some(order) = &self.order {
+            order.reset(start);
+        }
+        Arc::new(aligner.clone())
+    }
+
+    /// Waits until another batch may be read without exceeding the capacity of ordered output
+    fn reserve(&self) -> bool {
+        self.order.as_ref().is_none_or(|order| order.reserve())
+    }
+
+    /// Sends a batch to the workers, returning false once a worker failed
+    fn send(&self, job: Job) -> bool {
+        {
+            let mut state = self.progress.state.lock();
+            if state.error.is_some() {
+                return false;
+            }
+            state.n_pending += 1;
+        }
+        if self.jobs.send(job).is_err() {
+            // All workers stopped (after reporting their errors)
+            self.progress.complete();
+            return false;
+        }
+        true
+    }
+
+    /// Waits until all batches sent are mapped, returning the first error of a worker
+    fn wait(&self) -> Result<()> {
+        let mut state = self.progress.state.lock();
+        while state.n_pending > 0 && state.error.is_none() {
+            self.progress.done.wait(&mut state);
+        }
+        match state.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Body of a worker thread, mapping batches until the pool is finished
+fn work(
+    mut aligner: ParallelAlignment,
+    jobs: &Mutex<Receiver<Job>>,
+    recycled: &Sender<Batch>,
+    progress: &Progress,
+) -> Result<()> {
+    let mut input: Option<Arc<ParallelAlignment>> = None;
+    loop {
+        let Ok(job) = jobs.lock().recv() else {
+            return Ok(());
+        };
+        if !input
+            .as_ref()
+            .is_some_and(|input| Arc::ptr_eq(input, &job.input))
+        {
+            aligner.set_input(&job.input);
+            input = Some(job.input);
+        }
+        aligner.begin_batch(job.position.0, job.position.1);
+        let mut batch = job.batch;
+        batch.map(job.interleaved, &mut aligner)?;
+        ParallelProcessor::on_batch_complete(&mut aligner)?;
+        if !matches!(batch, Batch::Bq(..)) {
+            recycled.send(batch).ok();
+        }
+        progress.complete();
+    }
+}
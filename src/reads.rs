@@ -0,0 +1,319 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use binseq::{
+    bq::{BinseqHeader, BinseqWriter, BinseqWriterBuilder},
+    vbq::{VBinseqHeader, VBinseqWriter, VBinseqWriterBuilder},
+    Policy,
+};
+use parking_lot::Mutex;
+
+use crate::sam::Query;
+
+/// Policy for nucleotides which cannot be 2-bit encoded (BINSEQ outputs only)
+///
+/// Invalid nucleotides are replaced (with a fixed seed) rather than dropping the read.
+const POLICY: Policy = Policy::RandomDraw;
+
+/// Format of a read output (derived from the file extension)
+#[derive(Clone, Copy, PartialEq)]
+enum ReadFormat {
+    /// Fixed-length BINSEQ
+    Bq,
+
+    /// Variable-length BINSEQ
+    Vbq,
+
+    /// FASTQ (or FASTA without qualities), optionally compressed
+    Fastx(niffler::send::compression::Format),
+}
+impl ReadFormat {
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".bq") {
+            Self::Bq
+        } else if path.ends_with(".vbq") {
+            Self::Vbq
+        } else if path.ends_with(".gz") {
+            Self::Fastx(niffler::send::compression::Format::Gzip)
+        } else if path.ends_with(".bz2") {
+            Self::Fastx(niffler::send::compression::Format::Bzip)
+        } else if path.ends_with(".xz") {
+            Self::Fastx(niffler::send::compression::Format::Lzma)
+        } else if path.ends_with(".zst") {
+            Self::Fastx(niffler::send::compression::Format::Zstd)
+        } else {
+            Self::Fastx(niffler::send::compression::Format::No)
+        }
+    }
+}
+
+/// Record layout of a read output, taken from the first record written to it
+#[derive(Clone, Copy, PartialEq)]
+struct Layout {
+    slen: usize,
+    xlen: usize,
+    paired: bool,
+    quality: bool,
+}
+impl Layout {
+    fn of(query: &Query, mate: Option<&Query>) -> Self {
+        Self {
+            slen: query.seq.len(),
+            xlen: mate.map_or(0, |mate| mate.seq.len()),
+            paired: mate.is_some(),
+            quality: has_quality(query),
+        }
+    }
+    fn bq_header(&self) -> BinseqHeader {
+        if self.paired {
+            BinseqHeader::new_extended(self.slen as u32, self.xlen as u32)
+        } else {
+            BinseqHeader::new(self.slen as u32)
+        }
+    }
+    fn vbq_header(&self) -> VBinseqHeader {
+        VBinseqHeader::new(self.quality, true, self.paired)
+    }
+}
+
+fn has_quality(query: &Query) -> bool {
+    query.qual.is_some_and(|qual| !qual.is_empty())
+}
+
+/// Shared writer of a read output file
+enum Sink {
+    Bq(BinseqWriter<BufWriter<File>>),
+    Vbq(VBinseqWriter<BufWriter<File>>),
+    Fastx(Box<dyn Write + Send>),
+}
+
+/// Thread-local buffer of records (ingested into the sink once per batch)
+enum LocalBuffer {
+    Bq(BinseqWriter<Vec<u8>>),
+    Vbq(VBinseqWriter<Vec<u8>>),
+    Fastx(Vec<u8>),
+}
+
+struct SharedOutput {
+    path: String,
+    format: ReadFormat,
+
+    /// The sink is opened once the first record (and so the layout) is known
+    sink: Mutex<Option<(Layout, Sink)>>,
+}
+
+/// Writes the raw query records (with qualities and mates) to a BINSEQ, FASTQ, or FASTA file
+///
+/// The output format is chosen by the extension of the path (`.bq`, `.vbq`, or FASTQ/FASTA
+/// otherwise, compressed by a `.gz`, `.bz2`, `.xz`, or `.zst` extension). Pairs are written
+/// interleaved to FASTQ/FASTA outputs.
+pub struct ReadWriter {
+    shared: Arc<SharedOutput>,
+
+    /// Thread-local buffer and the layout it was created with
+    local: Option<(Layout, LocalBuffer)>,
+}
+impl Clone for ReadWriter {
+    /// Clones share the output file but each keeps its own buffer
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            local: None,
+        }
+    }
+}
+impl ReadWriter {
+    pub fn new(path: &str) -> Result<Self> {
+        // Fail early on paths which cannot be written
+        File::create(path)?;
+        Ok(Self {
+            shared: Arc::new(SharedOutput {
+                path: path.to_string(),
+                format: ReadFormat::from_path(path),
+                sink: Mutex::new(None),
+            }),
+            local: None,
+        })
+    }
+
+    /// Opens the sink on the first record and returns the layout of the output
+    fn layout(&self, query: &Query, mate: Option<&Query>) -> Result<Layout> {
+        let mut sink = self.shared.sink.lock();
+        if let Some((layout, _)) = sink.as_ref() {
+            return Ok(*layout);
+        }
+        let layout = Layout::of(query, mate);
+        *sink = Some((layout, self.open_sink(layout)?));
+        Ok(layout)
+    }
+
+    fn open_sink(&self, layout: Layout) -> Result<Sink> {
+        let file = BufWriter::new(File::create(&self.shared.path)?);
+        Ok(match self.shared.format {
+            ReadFormat::Bq => Sink::Bq(
+                BinseqWriterBuilder::default()
+                    .header(layout.bq_header())
+                    .policy(POLICY)
+                    .build(file)?,
+            ),
+            ReadFormat::Vbq => Sink::Vbq(
+                VBinseqWriterBuilder::default()
+                    .header(layout.vbq_header())
+                    .policy(POLICY)
+                    .build(file)?,
+            ),
+            ReadFormat::Fastx(compression) => Sink::Fastx(niffler::send::get_writer(
+                Box::new(file),
+                compression,
+                niffler::Level::Six,
+            )?),
+        })
+    }
+
+    fn local_buffer(layout: Layout, format: ReadFormat) -> Result<LocalBuffer> {
+        Ok(match format {
+            ReadFormat::Bq => LocalBuffer::Bq(
+                BinseqWriterBuilder::default()
+                    .header(layout.bq_header())
+                    .policy(POLICY)
+                    .headless(true)
+                    .build(Vec::new())?,
+            ),
+            ReadFormat::Vbq => LocalBuffer::Vbq(
+                VBinseqWriterBuilder::default()
+                    .header(layout.vbq_header())
+                    .policy(POLICY)
+                    .headless(true)
+                    .build(Vec::new())?,
+            ),
+            ReadFormat::Fastx(_) => LocalBuffer::Fastx(Vec::new()),
+        })
+    }
+
+    /// Buffers a query (and its mate) to be written with the next flush
+    pub fn write(&mut self, query: &Query, mate: Option<&Query>) -> Result<()> {
+        let layout = match &self.local {
+            Some((layout, _)) => *layout,
+            None => {
+                let layout = self.layout(query, mate)?;
+                self.local = Some((layout, Self::local_buffer(layout, self.shared.format)?));
+                layout
+            }
+        };
+        if layout.paired != mate.is_some() {
+            bail!(
+                "Unable to write {}: single-end and paired reads cannot be mixed in one file",
+                self.shared.path
+            );
+        }
+        if self.shared.format != ReadFormat::Bq && layout.quality != has_quality(query) {
+            bail!(
+                "Unable to write {}: reads with and without qualities cannot be mixed in one file",
+                self.shared.path
+            );
+        }
+        let Some((_, buffer)) = self.local.as_mut() else {
+            unreachable!("local buffer is initialized above");
+        };
+        let written = match (buffer, mate) {
+            (LocalBuffer::Bq(writer), None) => writer.write_nucleotides(0, query.seq),
+            (LocalBuffer::Bq(writer), Some(mate)) => writer.write_paired(0, query.seq, mate.seq),
+            (LocalBuffer::Vbq(writer), None) if layout.quality => {
+                writer.write_nucleotides_quality(0, query.seq, query.qual.unwrap_or_default())
+            }
+            (LocalBuffer::Vbq(writer), None) => writer.write_nucleotides(0, query.seq),
+            (LocalBuffer::Vbq(writer), Some(mate)) if layout.quality => writer
+                .write_nucleotides_quality_paired(
+                    0,
+                    query.seq,
+                    mate.seq,
+                    query.qual.unwrap_or_default(),
+                    mate.qual.unwrap_or_default(),
+                ),
+            (LocalBuffer::Vbq(writer), Some(mate)) => {
+                writer.write_nucleotides_paired(0, query.seq, mate.seq)
+            }
+            (LocalBuffer::Fastx(buf), None) => {
+                write_fastx(buf, query, None);
+                Ok(true)
+            }
+            (LocalBuffer::Fastx(buf), Some(mate)) => {
+                write_fastx(buf, query, Some(b"/1"));
+                write_fastx(buf, mate, Some(b"/2"));
+                Ok(true)
+            }
+        };
+        written.map_err(|err| match self.shared.format {
+            ReadFormat::Bq => anyhow!(
+                "Unable to write {}: {} (BQ requires reads of a fixed length, use .vbq instead)",
+                self.shared.path,
+                err
+            ),
+            _ => anyhow!("Unable to write {}: {}", self.shared.path, err),
+        })?;
+        Ok(())
+    }
+
+    /// Writes the buffered records to the output file
+    pub fn flush(&mut self) -> Result<()> {
+        let Some((_, buffer)) = self.local.as_mut() else {
+            return Ok(());
+        };
+        let mut sink = self.shared.sink.lock();
+        let Some((_, sink)) = sink.as_mut() else {
+            return Ok(());
+        };
+        match (sink, buffer) {
+            (Sink::Bq(sink), LocalBuffer::Bq(buffer)) => sink.ingest(buffer)?,
+            (Sink::Vbq(sink), LocalBuffer::Vbq(buffer)) => sink.ingest(buffer)?,
+            (Sink::Fastx(sink), LocalBuffer::Fastx(buffer)) => {
+                sink.write_all(buffer)?;
+                buffer.clear();
+            }
+            _ => unreachable!("local buffers match the format of their sink"),
+        }
+        Ok(())
+    }
+
+    /// Closes the output file
+    ///
+    /// Outputs without any records hold an empty file (or an empty VBQ file).
+    pub fn finish(&self) -> Result<()> {
+        let sink = match self.shared.sink.lock().take() {
+            Some((_, sink)) => sink,
+            None if self.shared.format == ReadFormat::Vbq => Sink::Vbq(
+                VBinseqWriterBuilder::default()
+                    .build(BufWriter::new(File::create(&self.shared.path)?))?,
+            ),
+            None => return Ok(()),
+        };
+        match sink {
+            Sink::Bq(mut writer) => writer.flush()?,
+            Sink::Vbq(mut writer) => writer.finish()?,
+            Sink::Fastx(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes a FASTQ record (or a FASTA record without qualities) with an optional name suffix
+fn write_fastx(buf: &mut Vec<u8>, query: &Query, suffix: Option<&[u8]>) {
+    let qual = query.qual.filter(|qual| !qual.is_empty());
+    buf.push(if qual.is_some() { b'@' } else { b'>' });
+    buf.extend_from_slice(query.name);
+    if let Some(suffix) = suffix {
+        buf.extend_from_slice(suffix);
+    }
+    buf.push(b'\n');
+    buf.extend_from_slice(query.seq);
+    buf.push(b'\n');
+    if let Some(qual) = qual {
+        buf.extend_from_slice(b"+\n");
+        buf.extend_from_slice(qual);
+        buf.push(b'\n');
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use minimap2::{Aligner, Built, Mapping};
+use serde::Serialize;
+
+use crate::io::{sample_name, transparent_reader};
+use crate::sam;
+
+/// A reference file and the index of the label its sequences are reported under
+struct ReferenceFile {
+    path: String,
+    label: usize,
+}
+
+/// Reference files to index, given as a comma-separated list of `[label=]path` entries
+///
+/// Files without a label are labelled after their file name. Several files may share a label.
+pub struct ReferenceFiles {
+    files: Vec<ReferenceFile>,
+    labels: Vec<String>,
+
+    /// Whether sequences are tagged with their label (several files or an explicit label)
+    labeled: bool,
+}
+impl ReferenceFiles {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut labels: Vec<String> = Vec::new();
+        let mut labeled = false;
+
+        // A path which exists is never split (it may contain a comma or `=` itself)
+        let entries = if Path::new(spec).exists() {
+            vec![spec]
+        } else {
+            spec.split(',').collect()
+        };
+        for entry in entries {
+            let (label, path) = match entry.split_once('=') {
+                Some((label, path)) if !Path::new(entry).exists() => {
+                    labeled = true;
+                    (label.to_string(), path)
+                }
+                _ => (sample_name(entry, false), entry),
+            };
+            if label.is_empty() || path.is_empty() {
+                bail!("Invalid reference entry (expected [label=]path): {}", entry);
+            }
+            let label = match labels.iter().position(|other| *other == label) {
+                Some(index) => index,
+                None => {
+                    labels.push(label);
+                    labels.len() - 1
+                }
+            };
+            files.push(ReferenceFile {
+                path: path.to_string(),
+                label,
+            });
+        }
+        Ok(Self {
+            labeled: labeled || files.len() > 1,
+            files,
+            labels,
+        })
+    }
+
+    /// Path of the file to index if it is a single file
+    pub fn single_path(&self) -> Option<&str> {
+        match &self.files[..] {
+            [file] => Some(&file.path),
+            _ => None,
+        }
+    }
+
+    /// Concatenates the reference files into one FASTA file in `tmp_dir`
+    ///
+    /// Returns the combined file (removed once dropped) and the label of each sequence.
+    pub fn combine(&self, tmp_dir: &Path) -> Result<(CombinedFasta, Vec<usize>)> {
+        let combined = CombinedFasta {
+            path: tmp_dir.join(format!("mmr-{}.references.fa", std::process::id())),
+        };
+        let mut writer = BufWriter::new(File::create(&combined.path)?);
+        let mut names = HashSet::new();
+        let mut sequence_labels = Vec::new();
+        for file in &self.files {
+            let mut reader = BufReader::new(transparent_reader(&file.path)?);
+            let mut line = Vec::new();
+            let mut first = true;
+            while reader.read_until(b'\n', &mut line)? > 0 {
+                if first && !line.starts_with(b">") {
+                    bail!("Reference file is not a FASTA file: {}", file.path);
+                }
+                first = false;
+                if let Some(header) = line.strip_prefix(b">") {
+                    let name = header
+                        .split(|c| c.is_ascii_whitespace())
+                        .next()
+                        .unwrap_or_default();
+                    if !names.insert(name.to_vec()) {
+                        bail!(
+                            "Reference sequence {} occurs more than once (in {})",
+                            String::from_utf8_lossy(name),
+                            file.path
+                        );
+                    }
+                    sequence_labels.push(file.label);
+                }
+                writer.write_all(&line)?;
+                if !line.ends_with(b"\n") {
+                    writer.write_all(b"\n")?;
+                }
+                line.clear();
+            }
+        }
+        writer.flush()?;
+        Ok((combined, sequence_labels))
+    }
+
+    /// Labels of the indexed sequences (only for labelled references)
+    ///
+    /// Without a combined file all sequences belong to the single reference file.
+    pub fn into_labels(
+        self,
+        aligner: &Aligner<Built>,
+        sequence_labels: Option<Vec<usize>>,
+    ) -> Result<Option<ReferenceLabels>> {
+        if !self.labeled {
+            return Ok(None);
+        }
+        let sequences = sam::reference_sequences(aligner);
+        let sequence_labels = sequence_labels.unwrap_or_else(|| vec![0; sequences.len()]);
+        if sequence_labels.len() != sequences.len() {
+            bail!(
+                "Combined reference holds {} sequences but {} were indexed",
+                sequence_labels.len(),
+                sequences.len()
+            );
+        }
+        let mut summaries = self
+            .labels
+            .into_iter()
+            .map(|label| LabelSummary {
+                label,
+                paths: Vec::new(),
+                sequences: 0,
+                total_length: 0,
+                reads: 0,
+            })
+            .collect::<Vec<_>>();
+        for file in self.files {
+            summaries[file.label].paths.push(file.path);
+        }
+        for (&label, (_, len)) in sequence_labels.iter().zip(&sequences) {
+            summaries[label].sequences += 1;
+            summaries[label].total_length += *len as u64;
+        }
+        Ok(Some(ReferenceLabels {
+            sequence_labels,
+            summaries,
+        }))
+    }
+}
+
+/// Combined FASTA of several reference files (removed once the index is built)
+pub struct CombinedFasta {
+    path: PathBuf,
+}
+impl CombinedFasta {
+    pub fn path(&self) -> &str {
+        self.path
+            .to_str()
+            .expect("temporary directory is a valid UTF-8 path")
+    }
+}
+impl Drop for CombinedFasta {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Label of every indexed sequence, tagged on alignments as `lb:Z:<label>`
+pub struct ReferenceLabels {
+    /// Label index of each sequence by target id
+    sequence_labels: Vec<usize>,
+    summaries: Vec<LabelSummary>,
+}
+impl ReferenceLabels {
+    pub fn n_labels(&self) -> usize {
+        self.summaries.len()
+    }
+
+    /// Label of the target sequence of a mapping
+    pub fn label(&self, mapping: &Mapping) -> Option<&str> {
+        let label = *self.sequence_labels.get(mapping.target_id as usize)?;
+        Some(&self.summaries[label].label)
+    }
+
+    /// Counts a read under the label of its primary alignment (if mapped)
+    pub fn count(&self, reads: &mut [u64], mappings: &[Mapping]) {
+        if let Some(label) = mappings
+            .iter()
+            .find(|mapping| mapping.is_primary || mapping.is_supplementary)
+            .and_then(|mapping| self.sequence_labels.get(mapping.target_id as usize))
+        {
+            reads[*label] += 1;
+        }
+    }
+
+    /// Summary of each label with the number of reads assigned to it
+    pub fn summary(&self, reads: &[u64]) -> Vec<LabelSummary> {
+        self.summaries
+            .iter()
+            .zip(reads)
+            .map(|(summary, &reads)| LabelSummary {
+                reads,
+                ..summary.clone()
+            })
+            .collect()
+    }
+}
+
+/// Reference files, sequences, and reads (by primary alignment, each mate counts) of a label
+#[derive(Clone, Serialize)]
+pub struct LabelSummary {
+    pub label: String,
+    pub paths: Vec<String>,
+    pub sequences: u64,
+    pub total_length: u64,
+    pub reads: u64,
+}
@@ -0,0 +1,404 @@
+use std::{borrow::Cow, ffi::CStr, io::Write};
+
+use anyhow::Result;
+use minimap2::{Aligner, Built, Mapping, Strand};
+
+use crate::mapper::Mapped;
+use crate::reference::ReferenceLabels;
+
+/// SAM flag: template has multiple segments
+pub const FLAG_PAIRED: u16 = 0x1;
+/// SAM flag: each segment is properly aligned
+pub const FLAG_PROPER_PAIR: u16 = 0x2;
+/// SAM flag: segment is unmapped
+pub const FLAG_UNMAPPED: u16 = 0x4;
+/// SAM flag: mate is unmapped
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+/// SAM flag: read is mapped to the reverse strand
+pub const FLAG_REVERSE: u16 = 0x10;
+/// SAM flag: mate is mapped to the reverse strand
+pub const FLAG_MATE_REVERSE: u16 = 0x20;
+/// SAM flag: first segment of the template
+pub const FLAG_READ1: u16 = 0x40;
+/// SAM flag: last segment of the template
+pub const FLAG_READ2: u16 = 0x80;
+/// SAM flag: secondary alignment
+pub const FLAG_SECONDARY: u16 = 0x100;
+/// SAM flag: supplementary alignment
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Query information required to write a SAM record
+pub struct Query<'a> {
+    pub name: &'a [u8],
+    pub seq: &'a [u8],
+    pub qual: Option<&'a [u8]>,
+
+    /// Mate information (paired queries only)
+    pub mate: Option<Mate<'a>>,
+}
+
+/// Mate information of a paired query
+#[derive(Clone, Copy)]
+pub struct Mate<'a> {
+    /// Whether the query is the first segment of the pair
+    pub first: bool,
+
+    /// Primary mapping of the mate (`None` if the mate is unmapped)
+    pub primary: Option<&'a Mapping>,
+
+    /// Mapping of the query which minimap2 paired with the mate (flagged as a proper pair)
+    pub proper: Option<&'a Mapping>,
+}
+impl<'a> Mate<'a> {
+    /// Builds the mate information of both segments of a pair from their mappings
+    pub fn pair(r1: &'a Mapped, r2: &'a Mapped) -> (Self, Self) {
+        (
+            Self {
+                first: true,
+                primary: primary_mapping(&r2.mappings),
+                proper: proper_mapping(r1),
+            },
+            Self {
+                first: false,
+                primary: primary_mapping(&r1.mappings),
+                proper: proper_mapping(r2),
+            },
+        )
+    }
+
+    /// Suffix appended to the query name in PAF output
+    pub fn suffix(&self) -> &'static str {
+        if self.first {
+            "/1"
+        } else {
+            "/2"
+        }
+    }
+}
+
+fn primary_mapping(mappings: &[Mapping]) -> Option<&Mapping> {
+    mappings
+        .iter()
+        .find(|mapping| mapping.is_primary && !mapping.is_supplementary)
+}
+
+fn proper_mapping(mapped: &Mapped) -> Option<&Mapping> {
+    mapped
+        .mappings
+        .iter()
+        .zip(&mapped.proper_frag)
+        .find_map(|(mapping, &proper)| proper.then_some(mapping))
+}
+
+/// Collects the (name, length) pairs of all sequences in the index
+pub fn reference_sequences(aligner: &Aligner<Built>) -> Vec<(String, u32)> {
+    (0..aligner.n_seq() as usize)
+        .filter_map(|i| aligner.get_seq(i))
+        .map(|seq| {
+            // SAFETY: names in a built index are valid null-terminated strings
+            let name = unsafe { CStr::from_ptr(seq.name) };
+            (name.to_string_lossy().into_owned(), seq.len)
+        })
+        .collect()
+}
+
+/// Writes the `@HD`, `@SQ`, `@RG`, and `@PG` header lines for the index
+///
+/// The `@HD` line declares coordinate order for sorted output and query grouping otherwise.
+pub fn write_header<W: Write>(
+    writer: &mut W,
+    aligner: &Aligner<Built>,
+    read_groups: &[String],
+    sorted: bool,
+) -> Result<()> {
+    if sorted {
+        writeln!(writer, "@HD\tVN:1.6\tSO:coordinate")?;
+    } else {
+        writeln!(writer, "@HD\tVN:1.6\tSO:unsorted\tGO:query")?;
+    }
+    for (name, len) in reference_sequences(aligner) {
+        writeln!(writer, "@SQ\tSN:{name}\tLN:{len}")?;
+    }
+    for read_group in read_groups {
+        writeln!(writer, "@RG\tID:{read_group}\tSM:{read_group}")?;
+    }
+    let command_line = std::env::args().collect::<Vec<_>>().join(" ");
+    writeln!(
+        writer,
+        "@PG\tID:mmr\tPN:mmr\tVN:{}\tCL:{}",
+        env!("CARGO_PKG_VERSION"),
+        command_line
+    )?;
+    Ok(())
+}
+
+/// Calculates the SAM flag of a mapping
+pub fn sam_flag(query: &Query, mapping: &Mapping) -> u16 {
+    let mut flag = 0;
+    if mapping.strand == Strand::Reverse {
+        flag |= FLAG_REVERSE;
+    }
+    if let Some(mate) = &query.mate {
+        flag |= FLAG_PAIRED;
+        flag |= if mate.first { FLAG_READ1 } else { FLAG_READ2 };
+        if mate
+            .proper
+            .is_some_and(|proper| std::ptr::eq(proper, mapping))
+        {
+            flag |= FLAG_PROPER_PAIR;
+        }
+        match mate.primary {
+            Some(primary) if primary.strand == Strand::Reverse => flag |= FLAG_MATE_REVERSE,
+            Some(_) => {}
+            None => flag |= FLAG_MATE_UNMAPPED,
+        }
+    }
+    if mapping.is_supplementary {
+        flag |= FLAG_SUPPLEMENTARY;
+    } else if !mapping.is_primary {
+        flag |= FLAG_SECONDARY;
+    }
+    flag
+}
+
+/// Calculates the SAM flag of a query without hits
+pub fn unmapped_flag(query: &Query) -> u16 {
+    let mut flag = FLAG_UNMAPPED;
+    if let Some(mate) = &query.mate {
+        flag |= FLAG_PAIRED;
+        flag |= if mate.first { FLAG_READ1 } else { FLAG_READ2 };
+        match mate.primary {
+            Some(primary) if primary.strand == Strand::Reverse => flag |= FLAG_MATE_REVERSE,
+            Some(_) => {}
+            None => flag |= FLAG_MATE_UNMAPPED,
+        }
+    }
+    flag
+}
+
+/// Primary mapping of the mate and the observed template length of a paired mapping
+///
+/// The template length is zero if the mate is mapped to a different reference.
+pub fn mate_fields<'a>(query: &Query<'a>, mapping: &Mapping) -> Option<(&'a Mapping, i32)> {
+    let mate = query.mate?;
+    let primary = mate.primary?;
+    if primary.target_id != mapping.target_id {
+        return Some((primary, 0));
+    }
+    let start = mapping.target_start.min(primary.target_start);
+    let end = mapping.target_end.max(primary.target_end);
+    let leftmost = mapping.target_start < primary.target_start
+        || (mapping.target_start == primary.target_start && mate.first);
+    let tlen = if leftmost { end - start } else { start - end };
+    Some((primary, tlen))
+}
+
+/// Writes one SAM record per mapping of the query
+///
+/// Records are tagged with the label of their reference and the read group if provided.
+pub fn write_records<W: Write>(
+    writer: &mut W,
+    query: &Query,
+    mappings: &[Mapping],
+    labels: Option<&ReferenceLabels>,
+    read_group: Option<&str>,
+) -> Result<()> {
+    for mapping in mappings {
+        let label = labels.and_then(|labels| labels.label(mapping));
+        write_record(writer, query, mapping, label, read_group)?;
+    }
+    Ok(())
+}
+
+/// Writes an unmapped SAM record for a query without hits
+///
+/// The record is placed at the primary mapping of its mate (if any), as recommended by the SAM
+/// specification.
+pub fn write_unmapped<W: Write>(
+    writer: &mut W,
+    query: &Query,
+    read_group: Option<&str>,
+) -> Result<()> {
+    let flag = unmapped_flag(query);
+    writer.write_all(query_name(query.name))?;
+    match query.mate.and_then(|mate| mate.primary) {
+        Some(mate) => write!(
+            writer,
+            "\t{}\t{}\t{}\t0\t*\t=\t{}\t0\t",
+            flag,
+            mate.target_name
+                .as_deref()
+                .map_or("*", |name| name.as_str()),
+            mate.target_start + 1,
+            mate.target_start + 1,
+        )?,
+        None => write!(writer, "\t{flag}\t*\t0\t0\t*\t*\t0\t0\t")?,
+    }
+
+    // SEQ QUAL
+    let (seq, qual) = oriented_seq_qual(query, flag);
+    if seq.is_empty() {
+        writer.write_all(b"*")?;
+    } else {
+        writer.write_all(&seq)?;
+    }
+    writer.write_all(b"\t")?;
+    match qual {
+        Some(qual) => writer.write_all(&qual)?,
+        None => writer.write_all(b"*")?,
+    }
+    if let Some(read_group) = read_group {
+        write!(writer, "\tRG:Z:{read_group}")?;
+    }
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_record<W: Write>(
+    writer: &mut W,
+    query: &Query,
+    mapping: &Mapping,
+    label: Option<&str>,
+    read_group: Option<&str>,
+) -> Result<()> {
+    let flag = sam_flag(query, mapping);
+
+    // QNAME FLAG RNAME POS MAPQ
+    writer.write_all(query_name(query.name))?;
+    write!(
+        writer,
+        "\t{}\t{}\t{}\t{}\t",
+        flag,
+        mapping
+            .target_name
+            .as_deref()
+            .map_or("*", |name| name.as_str()),
+        mapping.target_start + 1,
+        mapping.mapq,
+    )?;
+
+    // CIGAR
+    match mapping
+        .alignment
+        .as_ref()
+        .and_then(|aln| aln.cigar_str.as_deref())
+    {
+        Some(cigar) => writer.write_all(cigar.as_bytes())?,
+        None => writer.write_all(b"*")?,
+    }
+
+    // RNEXT PNEXT TLEN
+    match mate_fields(query, mapping) {
+        Some((mate, tlen)) => {
+            let rnext = if mate.target_id == mapping.target_id {
+                "="
+            } else {
+                mate.target_name
+                    .as_deref()
+                    .map_or("*", |name| name.as_str())
+            };
+            write!(writer, "\t{}\t{}\t{}\t", rnext, mate.target_start + 1, tlen)?;
+        }
+        None => writer.write_all(b"\t*\t0\t0\t")?,
+    }
+
+    // SEQ QUAL
+    let (seq, qual) = oriented_seq_qual(query, flag);
+    if seq.is_empty() {
+        writer.write_all(b"*")?;
+    } else {
+        writer.write_all(&seq)?;
+    }
+    writer.write_all(b"\t")?;
+    match qual {
+        Some(qual) => writer.write_all(&qual)?,
+        None => writer.write_all(b"*")?,
+    }
+
+    // Optional tags
+    if let Some(alignment) = &mapping.alignment {
+        write!(writer, "\tNM:i:{}", alignment.nm)?;
+        if let Some(score) = alignment.alignment_score {
+            write!(writer, "\tAS:i:{score}")?;
+        }
+    }
+    writer.write_all(if mapping.is_primary || mapping.is_supplementary {
+        b"\ttp:A:P"
+    } else {
+        b"\ttp:A:S"
+    })?;
+    if let Some(alignment) = &mapping.alignment {
+        if let Some(cs) = &alignment.cs {
+            write!(writer, "\tcs:Z:{cs}")?;
+        }
+        if let Some(md) = &alignment.md {
+            write!(writer, "\tMD:Z:{md}")?;
+        }
+    }
+    if let Some(label) = label {
+        write!(writer, "\tlb:Z:{label}")?;
+    }
+    if let Some(read_group) = read_group {
+        write!(writer, "\tRG:Z:{read_group}")?;
+    }
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Sequence and qualities of the query in the orientation of the alignment
+///
+/// Both are omitted for secondary alignments and missing qualities are returned as `None`.
+pub fn oriented_seq_qual<'a>(
+    query: &Query<'a>,
+    flag: u16,
+) -> (Cow<'a, [u8]>, Option<Cow<'a, [u8]>>) {
+    if flag & FLAG_SECONDARY != 0 {
+        return (Cow::Borrowed(&[]), None);
+    }
+    let qual = query.qual.filter(|qual| !qual.is_empty());
+    if flag & FLAG_REVERSE != 0 {
+        (
+            Cow::Owned(reverse_complement(query.seq)),
+            qual.map(|qual| Cow::Owned(qual.iter().rev().copied().collect())),
+        )
+    } else {
+        (Cow::Borrowed(query.seq), qual.map(Cow::Borrowed))
+    }
+}
+
+/// Trims a record header to its first whitespace-delimited word
+pub fn query_name(header: &[u8]) -> &[u8] {
+    header
+        .split(|c| c.is_ascii_whitespace())
+        .next()
+        .unwrap_or(header)
+}
+
+/// Trims a record header to the name of its template
+///
+/// Trailing `/1` and `/2` mate suffixes are removed.
+pub fn template_name(header: &[u8]) -> &[u8] {
+    let name = query_name(header);
+    match name {
+        [head @ .., b'/', b'1' | b'2'] => head,
+        _ => name,
+    }
+}
+
+/// Reverse complements a nucleotide sequence
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            _ => b'N',
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::Serialize;
+
+/// Selects a reproducible random subset of the query records
+///
+/// Whether a record is kept depends only on the seed and the record (its index in a BINSEQ
+/// file, or its name in FASTQ/FASTA), so the same records are selected regardless of the
+/// number of threads. Pairs are selected as one record.
+pub struct Sampler {
+    /// Records are kept if their hash is below the threshold (all records without a fraction)
+    threshold: Option<u64>,
+
+    fraction: Option<f64>,
+    seed: u64,
+
+    /// Maximum number of records kept from each query input
+    max_records: Option<u64>,
+
+    /// Number of FASTQ/FASTA records kept from the current query input
+    n_selected: AtomicU64,
+
+    /// Number of records kept and skipped from all query inputs
+    n_sampled: AtomicU64,
+    n_skipped: AtomicU64,
+}
+impl Sampler {
+    pub fn new(fraction: Option<f64>, max_records: Option<u64>, seed: u64) -> Self {
+        Self {
+            threshold: fraction
+                .filter(|&fraction| fraction < 1.0)
+                .map(|fraction| (fraction * u64::MAX as f64) as u64),
+            fraction,
+            seed,
+            max_records,
+            n_selected: AtomicU64::new(0),
+            n_sampled: AtomicU64::new(0),
+            n_skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts counting the records of a new query input
+    pub fn reset(&self) {
+        self.n_selected.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether the BINSEQ record at an index is mapped
+    ///
+    /// `--max-reads` is applied beforehand by shortening the range of records (`limit_range`).
+    pub fn select_index(&self, index: u64) -> bool {
+        self.count(self.sampled(self.hash_index(index)))
+    }
+
+    /// Whether the FASTQ/FASTA record (or pair) with a name is mapped, or `None` once
+    /// `--max-reads` records of the input were selected
+    ///
+    /// Records are selected by the reading thread in the order of the input, so the records cut
+    /// off by `--max-reads` are independent of thread scheduling.
+    pub fn select_name(&self, name: &[u8]) -> Option<bool> {
+        if self
+            .max_records
+            .is_some_and(|max_records| self.n_selected.load(Ordering::Relaxed) >= max_records)
+        {
+            return None;
+        }
+        let selected = self.sampled(self.hash_name(name));
+        if selected {
+            self.n_selected.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(self.count(selected))
+    }
+
+    /// Shortens a range of BINSEQ records to end after the last record within `--max-reads`
+    ///
+    /// This keeps the records selected from a BINSEQ file independent of thread scheduling.
+    pub fn limit_range(&self, range: Range<usize>) -> Range<usize> {
+        let Some(max_records) = self.max_records else {
+            return range;
+        };
+        let mut n_selected = 0;
+        for index in range.clone() {
+            if n_selected == max_records {
+                return range.start..index;
+            }
+            if self.sampled(self.hash_index(index as u64)) {
+                n_selected += 1;
+            }
+        }
+        range
+    }
+
+    pub fn summary(&self) -> SamplingSummary {
+        SamplingSummary {
+            fraction: self.fraction,
+            max_reads: self.max_records,
+            seed: self.seed,
+            sampled_records: self.n_sampled.load(Ordering::Relaxed) as usize,
+            skipped_records: self.n_skipped.load(Ordering::Relaxed) as usize,
+        }
+    }
+
+    fn sampled(&self, hash: u64) -> bool {
+        self.threshold.is_none_or(|threshold| hash < threshold)
+    }
+
+    /// Counts a record as sampled or skipped
+    fn count(&self, selected: bool) -> bool {
+        let counter = if selected {
+            &self.n_sampled
+        } else {
+            &self.n_skipped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        selected
+    }
+
+    fn hash_index(&self, index: u64) -> u64 {
+        mix(self.seed ^ mix(index))
+    }
+
+    /// FNV-1a hash of the name, mixed with the seed
+    fn hash_name(&self, name: &[u8]) -> u64 {
+        let hash = name.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        mix(self.seed ^ mix(hash))
+    }
+}
+
+/// Finalizer of the SplitMix64 generator
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Subsampling options and the number of records mapped and skipped
+#[derive(Serialize)]
+pub struct SamplingSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fraction: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_reads: Option<u64>,
+    pub seed: u64,
+    pub sampled_records: usize,
+    pub skipped_records: usize,
+}
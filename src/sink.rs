@@ -0,0 +1,197 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::Compression;
+use parking_lot::Mutex;
+
+use crate::bgzf;
+use crate::cli::OutputCompression;
+
+/// Number of buffers which may be queued for the writer thread (and kept for reuse)
+const QUEUE_CAPACITY: usize = 64;
+
+/// Capacity of the buffered writer owned by the writer thread
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Level of Zstandard-compressed output (the zstd default)
+const ZSTD_LEVEL: i32 = 3;
+
+enum Message {
+    Data(Vec<u8>),
+    Finish,
+}
+
+/// Long-lived output fed through a bounded channel
+///
+/// A dedicated thread owns the output (a file or stdout) and writes buffers in the order they
+/// are sent. Written buffers are returned to a pool so workers can reuse their allocations.
+/// Clones share the same output.
+///
+/// Block compression (bgzip and zstd) is applied by the sending thread, so it runs in parallel
+/// across workers, while stream compression (gzip, bzip2, and xz) is applied by the writer thread.
+#[derive(Clone)]
+pub struct OutputSink {
+    tx: SyncSender<Message>,
+
+    compression: OutputCompression,
+
+    /// Empty buffers returned by the writer thread
+    pool: Arc<Mutex<Vec<Vec<u8>>>>,
+
+    /// Writer thread (taken once the output is finished or failed)
+    thread: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
+}
+impl OutputSink {
+    /// Creates (or truncates) the output file, or writes to stdout without a path
+    pub fn create(path: Option<&str>, compression: OutputCompression) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(io::stdout()),
+        };
+        Self::new(writer, compression)
+    }
+
+    pub fn new(writer: Box<dyn Write + Send>, compression: OutputCompression) -> Result<Self> {
+        let format = match compression {
+            OutputCompression::Gzip => niffler::send::compression::Format::Gzip,
+            OutputCompression::Bzip2 => niffler::send::compression::Format::Bzip,
+            OutputCompression::Xz => niffler::send::compression::Format::Lzma,
+            _ => niffler::send::compression::Format::No,
+        };
+        let writer = niffler::send::get_writer(writer, format, niffler::Level::Six)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let pool = Arc::new(Mutex::new(Vec::new()));
+        let thread = {
+            let pool = pool.clone();
+            thread::spawn(move || write_messages(writer, rx, pool))
+        };
+        Ok(Self {
+            tx,
+            compression,
+            pool,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        })
+    }
+
+    /// Takes an empty buffer from the pool (or allocates a new one)
+    pub fn buffer(&self) -> Vec<u8> {
+        self.pool.lock().pop().unwrap_or_default()
+    }
+
+    /// Returns an unsent buffer to the pool
+    pub fn recycle(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        recycle(&self.pool, buffer);
+    }
+
+    /// Compresses (with block compression) and queues a buffer to be written
+    ///
+    /// Blocks while the queue is full.
+    pub fn send(&self, data: Vec<u8>) -> Result<()> {
+        let data = self.compress(data)?;
+        self.queue(data)
+    }
+
+    /// Applies block compression (bgzip and zstd) to a buffer, leaving it as is otherwise
+    pub fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if data.is_empty() {
+            return Ok(data);
+        }
+        Ok(match self.compression {
+            OutputCompression::Bgzip => {
+                let mut compressed = self.buffer();
+                bgzf::compress_blocks(&data, Compression::default(), &mut compressed)?;
+                self.recycle(data);
+                compressed
+            }
+            OutputCompression::Zstd => {
+                let mut compressed = self.buffer();
+                zstd::stream::copy_encode(data.as_slice(), &mut compressed, ZSTD_LEVEL)?;
+                self.recycle(data);
+                compressed
+            }
+            _ => data,
+        })
+    }
+
+    /// Queues an already compressed buffer to be written
+    ///
+    /// Blocks while the queue is full.
+    pub fn queue(&self, data: Vec<u8>) -> Result<()> {
+        if data.is_empty() {
+            self.recycle(data);
+            return Ok(());
+        }
+        if self.tx.send(Message::Data(data)).is_err() {
+            // The writer thread only stops early on errors
+            self.join()?;
+            bail!("Unable to write to a closed output");
+        }
+        Ok(())
+    }
+
+    /// Writes all queued buffers, flushes the output, and stops the writer thread
+    pub fn finish(&self) -> Result<()> {
+        if self.compression == OutputCompression::Bgzip {
+            self.queue(bgzf::EOF_BLOCK.to_vec())?;
+        }
+        self.tx.send(Message::Finish).ok();
+        self.join()
+    }
+
+    fn join(&self) -> Result<()> {
+        match self.thread.lock().take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow!("Output writer thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Each write is sent to the writer thread as one buffer
+impl Write for OutputSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer();
+        buffer.extend_from_slice(data);
+        self.send(buffer).map_err(io::Error::other)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn recycle(pool: &Mutex<Vec<Vec<u8>>>, buffer: Vec<u8>) {
+    let mut pool = pool.lock();
+    if pool.len() < QUEUE_CAPACITY {
+        pool.push(buffer);
+    }
+}
+
+/// Body of the writer thread
+///
+/// Stops at the finish message, once all senders are dropped, or on the first write error.
+fn write_messages(
+    writer: Box<dyn Write + Send>,
+    rx: Receiver<Message>,
+    pool: Arc<Mutex<Vec<Vec<u8>>>>,
+) -> Result<()> {
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, writer);
+    while let Ok(Message::Data(mut buffer)) = rx.recv() {
+        writer.write_all(&buffer)?;
+        buffer.clear();
+        recycle(&pool, buffer);
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -1,7 +1,12 @@
-use std::time::Instant;
+use std::{collections::BTreeMap, time::Instant};
 
+use minimap2::{IdxOpt, Mapping};
 use serde::Serialize;
 
+use crate::filter::FilterStats;
+use crate::reference::LabelSummary;
+use crate::sample::SamplingSummary;
+
 #[derive(Serialize)]
 pub struct Runtime {
     #[serde(rename = "elapsed_total_sec")]
@@ -14,9 +19,40 @@ pub struct Runtime {
     pub n_records: usize,
     #[serde(rename = "throughput_records_per_sec")]
     pub throughput: f64,
+    #[serde(rename = "kmer_size")]
+    pub k: i16,
+    #[serde(rename = "window_size")]
+    pub w: i16,
+    pub inputs: Vec<InputStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SamplingSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<FilterStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub references: Option<Vec<LabelSummary>>,
+    pub mapping: MappingSummary,
+}
+
+/// Number of records mapped from a single query input
+#[derive(Serialize)]
+pub struct InputStats {
+    pub sample: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mate_path: Option<String>,
+    #[serde(rename = "total_records")]
+    pub n_records: usize,
 }
 impl Runtime {
-    pub fn new(t_init: Instant, t_map: Instant, n_records: usize) -> Self {
+    pub fn new(
+        t_init: Instant,
+        t_map: Instant,
+        n_records: usize,
+        idxopt: IdxOpt,
+        inputs: Vec<InputStats>,
+        sampling: Option<SamplingSummary>,
+        mapping: MappingSummary,
+    ) -> Self {
         let e_total = t_init.elapsed().as_secs_f64();
         let e_init = (t_map - t_init).as_secs_f64();
         let e_map = t_map.elapsed().as_secs_f64();
@@ -27,6 +63,163 @@ impl Runtime {
             e_map,
             n_records,
             throughput,
+            k: idxopt.k,
+            w: idxopt.w,
+            inputs,
+            sampling,
+            filters: None,
+            references: None,
+            mapping,
+        }
+    }
+}
+
+/// Mapping QC counters of the query reads (each mate counts as a read)
+///
+/// Collected by every worker while writing its batch and merged into the run totals.
+#[derive(Clone, Default)]
+pub struct MappingStats {
+    n_reads: u64,
+    n_mapped: u64,
+    n_multi_mapped: u64,
+    n_primary: u64,
+    n_secondary: u64,
+    n_supplementary: u64,
+
+    /// Number of primary alignments by MAPQ
+    mapq: Vec<u64>,
+
+    /// Number of reads by length
+    read_lengths: BTreeMap<usize, u64>,
+
+    total_bases: u64,
+    aligned_bases: u64,
+    matched_bases: u64,
+}
+impl MappingStats {
+    /// Counts a read and its mappings
+    pub fn add_read(&mut self, len: usize, mappings: &[Mapping]) {
+        self.n_reads += 1;
+        self.total_bases += len as u64;
+        *self.read_lengths.entry(len).or_default() += 1;
+        if mappings.is_empty() {
+            return;
+        }
+        self.n_mapped += 1;
+        let mut n_secondary = 0;
+        for mapping in mappings {
+            if mapping.is_supplementary {
+                self.n_supplementary += 1;
+            } else if mapping.is_primary {
+                self.n_primary += 1;
+                let mapq = mapping.mapq as usize;
+                if self.mapq.len() <= mapq {
+                    self.mapq.resize(mapq + 1, 0);
+                }
+                self.mapq[mapq] += 1;
+            } else {
+                n_secondary += 1;
+                continue;
+            }
+            self.aligned_bases += (mapping.query_end - mapping.query_start) as u64;
+            self.matched_bases += mapping.match_len as u64;
         }
+        self.n_secondary += n_secondary;
+        if n_secondary > 0 {
+            self.n_multi_mapped += 1;
+        }
+    }
+
+    /// Adds the counters of another worker, leaving its counters empty
+    pub fn absorb(&mut self, other: &mut Self) {
+        let other = std::mem::take(other);
+        self.n_reads += other.n_reads;
+        self.n_mapped += other.n_mapped;
+        self.n_multi_mapped += other.n_multi_mapped;
+        self.n_primary += other.n_primary;
+        self.n_secondary += other.n_secondary;
+        self.n_supplementary += other.n_supplementary;
+        if self.mapq.len() < other.mapq.len() {
+            self.mapq.resize(other.mapq.len(), 0);
+        }
+        for (total, count) in self.mapq.iter_mut().zip(other.mapq) {
+            *total += count;
+        }
+        for (len, count) in other.read_lengths {
+            *self.read_lengths.entry(len).or_default() += count;
+        }
+        self.total_bases += other.total_bases;
+        self.aligned_bases += other.aligned_bases;
+        self.matched_bases += other.matched_bases;
     }
+
+    pub fn summary(&self) -> MappingSummary {
+        MappingSummary {
+            n_reads: self.n_reads,
+            n_mapped: self.n_mapped,
+            n_unmapped: self.n_reads - self.n_mapped,
+            n_multi_mapped: self.n_multi_mapped,
+            n_primary: self.n_primary,
+            n_secondary: self.n_secondary,
+            n_supplementary: self.n_supplementary,
+            mean_read_length: if self.n_reads > 0 {
+                self.total_bases as f64 / self.n_reads as f64
+            } else {
+                0.0
+            },
+            read_length_n50: self.read_length_n50(),
+            total_bases: self.total_bases,
+            aligned_bases: self.aligned_bases,
+            matched_bases: self.matched_bases,
+            mapq: self
+                .mapq
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(mapq, count)| (mapq, *count))
+                .collect(),
+        }
+    }
+
+    /// Length of the shortest read among the longest reads covering half of all bases
+    fn read_length_n50(&self) -> usize {
+        let mut cumulative = 0;
+        for (len, count) in self.read_lengths.iter().rev() {
+            cumulative += *len as u64 * count;
+            if cumulative * 2 >= self.total_bases {
+                return *len;
+            }
+        }
+        0
+    }
+}
+
+/// Mapping QC of a run (similar to `samtools flagstat` and `samtools stats`)
+#[derive(Serialize)]
+pub struct MappingSummary {
+    #[serde(rename = "reads")]
+    pub n_reads: u64,
+    #[serde(rename = "mapped_reads")]
+    pub n_mapped: u64,
+    #[serde(rename = "unmapped_reads")]
+    pub n_unmapped: u64,
+    /// Mapped reads with at least one secondary alignment
+    #[serde(rename = "multi_mapped_reads")]
+    pub n_multi_mapped: u64,
+    #[serde(rename = "primary_alignments")]
+    pub n_primary: u64,
+    #[serde(rename = "secondary_alignments")]
+    pub n_secondary: u64,
+    #[serde(rename = "supplementary_alignments")]
+    pub n_supplementary: u64,
+    pub mean_read_length: f64,
+    pub read_length_n50: usize,
+    pub total_bases: u64,
+    /// Query bases spanned by primary and supplementary alignments (overlaps counted twice)
+    pub aligned_bases: u64,
+    /// Matching bases of primary and supplementary alignments
+    pub matched_bases: u64,
+    /// Number of primary alignments by MAPQ
+    #[serde(rename = "mapq_histogram")]
+    pub mapq: BTreeMap<usize, u64>,
 }